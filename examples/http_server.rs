@@ -1,34 +1,19 @@
-use std::error::Error;
+use std::{error::Error, time::Duration};
 
-use proxy_stream::Http;
-use proxy_stream::{DestinationAddress, HttpConfig};
-use tokio::net::TcpStream;
+use proxy_stream::{HttpConfig, Server};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let listener = tokio::net::TcpListener::bind("127.0.0.1:8080").await?;
-    loop {
-        let stream = listener.accept().await?;
-        let mut http = Http::new_server(HttpConfig::default(), stream.0);
-        tokio::spawn(async move {
-            let http_stream = match http.accept().await {
-                Ok(http_stream) => http_stream,
-                Err(e) => {
-                    eprintln!("{}", e);
-                    return;
-                }
-            };
-            let Ok(socket) = (match http_stream.addr() {
-                DestinationAddress::Domain(host, port) => {
-                    TcpStream::connect(format!("{}:{}", host, port)).await
-                }
-                DestinationAddress::Ip(addr) => TcpStream::connect(addr).await,
-            }) else {
-                return;
-            };
-            if let Err(e) = http_stream.serve(socket).await {
-                eprintln!("{}", e);
-            };
-        });
-    }
+    let config = HttpConfig::builder()
+        .connect_timeout(Duration::from_secs(10))
+        .build()?;
+    Server::new(listener)
+        .max_connections(1024)
+        .shutdown_timeout(Duration::from_secs(10))
+        .serve_http_direct(config, async {
+            _ = tokio::signal::ctrl_c().await;
+        })
+        .await;
+    Ok(())
 }
@@ -0,0 +1,39 @@
+use ipnet::IpNet;
+
+use crate::PeerInfo;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct Acl {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl Acl {
+    pub fn allow(mut self, net: IpNet) -> Self {
+        self.allow.push(net);
+        self
+    }
+
+    pub fn deny(mut self, net: IpNet) -> Self {
+        self.deny.push(net);
+        self
+    }
+
+    // Deny entries win over allow entries; an empty allow list admits every
+    // address. Peers without an IP address (e.g. Unix sockets) are only
+    // subject to a non-empty allow list.
+    pub fn is_allowed(&self, peer: &PeerInfo) -> bool {
+        match peer.ip() {
+            Some(ip) => {
+                !self.deny.iter().any(|net| net.contains(&ip))
+                    && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+            }
+            None => self.allow.is_empty(),
+        }
+    }
+}
@@ -30,6 +30,42 @@ impl DestinationAddress {
         }
     }
 
+    pub fn host(&self) -> String {
+        match self {
+            DestinationAddress::Domain(domain, _) => domain.trim_end_matches('.').to_string(),
+            DestinationAddress::Ip(addr) => addr.ip().to_string(),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            DestinationAddress::Domain(_, port) => *port,
+            DestinationAddress::Ip(addr) => addr.port(),
+        }
+    }
+
+    pub async fn connect(&self) -> std::io::Result<tokio::net::TcpStream> {
+        match self {
+            DestinationAddress::Domain(domain, port) => {
+                tokio::net::TcpStream::connect((domain.as_str(), *port)).await
+            }
+            DestinationAddress::Ip(addr) => tokio::net::TcpStream::connect(addr).await,
+        }
+    }
+
+    // The first address the system resolver returns for a domain.
+    pub(crate) async fn resolve(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            DestinationAddress::Domain(domain, port) => {
+                tokio::net::lookup_host((domain.as_str(), *port))
+                    .await?
+                    .next()
+                    .ok_or_else(|| std::io::ErrorKind::NotFound.into())
+            }
+            DestinationAddress::Ip(addr) => Ok(*addr),
+        }
+    }
+
     // pub fn from_str(s: &str) -> Result<Self, AddrError> {
 
     // }
@@ -93,6 +129,18 @@ impl ToSocketDestination for SocketAddr {
     }
 }
 
+impl ToSocketDestination for DestinationAddress {
+    fn to_destination_address(&self) -> Result<DestinationAddress, AddrError> {
+        Ok(self.clone())
+    }
+}
+
+impl ToSocketDestination for &DestinationAddress {
+    fn to_destination_address(&self) -> Result<DestinationAddress, AddrError> {
+        Ok((*self).clone())
+    }
+}
+
 impl ToSocketDestination for &str {
     fn to_destination_address(&self) -> Result<DestinationAddress, AddrError> {
         if let Ok(ip) = self.parse::<SocketAddr>() {
@@ -190,3 +238,18 @@ impl FromStr for DestinationAddress {
         }
     }
 }
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for DestinationAddress {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for DestinationAddress {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        DestinationAddress::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
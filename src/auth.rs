@@ -0,0 +1,97 @@
+use std::{collections::HashMap, fmt, future::Future, pin::Pin};
+
+use base64::Engine;
+
+use crate::{error::ConfigError, BandwidthLimit, PeerInfo};
+
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Credentials {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        // RFC 1929 carries both fields with a one byte length prefix.
+        if self.username.is_empty() || self.username.len() > 255 || self.password.len() > 255 {
+            return Err(ConfigError::InvalidCredentials);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn from_basic(header: &str) -> Option<Self> {
+        let (scheme, token) = header.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(token.trim())
+            .ok()?;
+        let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+        Some(Credentials::new(username, password))
+    }
+
+    pub(crate) fn to_basic(&self) -> String {
+        format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD
+                .encode(format!("{}:{}", self.username, self.password))
+        )
+    }
+}
+
+pub trait Authenticator: Send + Sync + 'static {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a Credentials,
+        peer: &'a PeerInfo,
+    ) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>>;
+
+    // Bandwidth limit shared by all relays of an authenticated user.
+    fn bandwidth(&self, _user: &str) -> Option<BandwidthLimit> {
+        None
+    }
+}
+
+impl fmt::Debug for dyn Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Authenticator")
+    }
+}
+
+impl Authenticator for Credentials {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a Credentials,
+        _peer: &'a PeerInfo,
+    ) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        Box::pin(async move { self == credentials })
+    }
+}
+
+impl Authenticator for HashMap<String, String> {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a Credentials,
+        _peer: &'a PeerInfo,
+    ) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        Box::pin(async move { self.get(&credentials.username) == Some(&credentials.password) })
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::Arc;
+
+use crate::{
+    address::ToSocketDestination,
+    error::{ProxyStreamError, TimeoutKind},
+    socks4,
+    timeout::{connect, timeout},
+    AsyncSocket, DestinationAddress, Http, HttpConfig, Socks5, SocksConfig, Timeouts,
+};
+
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "protocol", rename_all = "snake_case")
+)]
+pub enum Hop {
+    Socks5 {
+        addr: DestinationAddress,
+        #[cfg_attr(feature = "serde", serde(default))]
+        config: Arc<SocksConfig>,
+    },
+    Http {
+        addr: DestinationAddress,
+        #[cfg_attr(feature = "serde", serde(default))]
+        config: Arc<HttpConfig>,
+    },
+    Socks4a {
+        addr: DestinationAddress,
+        #[cfg_attr(feature = "serde", serde(default))]
+        user: Option<String>,
+        // Only `connect` and `handshake` apply.
+        #[cfg_attr(feature = "serde", serde(default))]
+        timeouts: Timeouts,
+    },
+}
+
+impl Hop {
+    pub fn addr(&self) -> &DestinationAddress {
+        match self {
+            Hop::Socks5 { addr, .. } | Hop::Http { addr, .. } | Hop::Socks4a { addr, .. } => addr,
+        }
+    }
+
+    // Asks the proxy behind `stream` for a tunnel to `target`.
+    async fn tunnel(
+        &self,
+        stream: Box<dyn AsyncSocket>,
+        target: &DestinationAddress,
+    ) -> Result<Box<dyn AsyncSocket>, ProxyStreamError> {
+        match self {
+            Hop::Socks5 { config, .. } => Ok(Box::new(
+                Socks5::new_client(config.clone(), stream)
+                    .connect(target)
+                    .await?
+                    .proxied_stream()
+                    .await?,
+            )),
+            Hop::Http { config, .. } => Ok(Box::new(
+                Http::new_client(config.clone(), stream)
+                    .connect(target)
+                    .await?,
+            )),
+            Hop::Socks4a { user, timeouts, .. } => Ok(Box::new(
+                timeout(
+                    timeouts.handshake,
+                    TimeoutKind::Handshake,
+                    socks4::connect(stream, target, user.as_deref()),
+                )
+                .await?,
+            )),
+        }
+    }
+
+    pub(crate) fn connect_timeout(&self) -> Option<std::time::Duration> {
+        match self {
+            Hop::Socks5 { config, .. } => config.timeouts.connect,
+            Hop::Http { config, .. } => config.timeouts.connect,
+            Hop::Socks4a { timeouts, .. } => timeouts.connect,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct Chain {
+    hops: Vec<Hop>,
+}
+
+impl Chain {
+    pub fn new() -> Self {
+        Chain::default()
+    }
+
+    pub fn hop(mut self, hop: Hop) -> Self {
+        self.hops.push(hop);
+        self
+    }
+
+    pub fn socks5(self, addr: DestinationAddress, config: impl Into<Arc<SocksConfig>>) -> Self {
+        self.hop(Hop::Socks5 {
+            addr,
+            config: config.into(),
+        })
+    }
+
+    pub fn http(self, addr: DestinationAddress, config: impl Into<Arc<HttpConfig>>) -> Self {
+        self.hop(Hop::Http {
+            addr,
+            config: config.into(),
+        })
+    }
+
+    pub fn socks4a(
+        self,
+        addr: DestinationAddress,
+        user: Option<String>,
+        timeouts: Timeouts,
+    ) -> Self {
+        self.hop(Hop::Socks4a {
+            addr,
+            user,
+            timeouts,
+        })
+    }
+
+    pub fn hops(&self) -> &[Hop] {
+        &self.hops
+    }
+
+    pub async fn connect(
+        &self,
+        target: impl ToSocketDestination,
+    ) -> Result<Box<dyn AsyncSocket>, ProxyStreamError> {
+        let target = target.to_destination_address()?;
+        let Some(first) = self.hops.first() else {
+            return connect(&target, None)
+                .await
+                .map(|stream| Box::new(stream) as Box<dyn AsyncSocket>)
+                .map_err(|e| ProxyStreamError::upstream(&target, e));
+        };
+        let mut stream: Box<dyn AsyncSocket> = Box::new(
+            connect(first.addr(), first.connect_timeout())
+                .await
+                .map_err(|e| ProxyStreamError::upstream(first.addr(), e))?,
+        );
+        for (i, hop) in self.hops.iter().enumerate() {
+            let next = self.hops.get(i + 1).map(Hop::addr).unwrap_or(&target);
+            stream = hop
+                .tunnel(stream, next)
+                .await
+                .map_err(|e| e.at_upstream(hop.addr()))?;
+        }
+        Ok(stream)
+    }
+}
@@ -0,0 +1,211 @@
+// Lets hyper based HTTP clients reach origins through the proxies a
+// `ProxySelector` picks. Every connection is a tunnel, so requests keep
+// their origin form even when the proxy speaks HTTP.
+
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use hyper::Uri;
+use hyper_util::{
+    client::legacy::connect::{Connected, Connection},
+    rt::TokioIo,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    error::{address::AddrError, ConfigError, ProxyStreamError},
+    AsyncSocket, Chain, DestinationAddress, ProxySelector, ProxySpec,
+};
+
+#[derive(Debug, Clone)]
+pub struct ProxyConnector {
+    selector: Arc<ProxySelector>,
+}
+
+impl ProxyConnector {
+    pub fn new(selector: ProxySelector) -> Self {
+        ProxyConnector {
+            selector: Arc::new(selector),
+        }
+    }
+}
+
+impl From<ProxySelector> for ProxyConnector {
+    fn from(selector: ProxySelector) -> Self {
+        ProxyConnector::new(selector)
+    }
+}
+
+// Every destination goes through `chain`.
+impl From<Chain> for ProxyConnector {
+    fn from(chain: Chain) -> Self {
+        ProxyConnector::new(ProxySelector::new().all_proxy(chain))
+    }
+}
+
+impl TryFrom<&ProxySpec> for ProxyConnector {
+    type Error = ConfigError;
+
+    fn try_from(spec: &ProxySpec) -> Result<Self, Self::Error> {
+        spec.chain().map(Into::into)
+    }
+}
+
+impl tower_service::Service<Uri> for ProxyConnector {
+    type Response = TokioIo<ProxyTunnel>;
+    type Error = ProxyStreamError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let selector = self.selector.clone();
+        Box::pin(async move {
+            let scheme = uri.scheme_str().unwrap_or("http");
+            let addr = destination(&uri, scheme)?;
+            let stream = selector.connect(scheme, addr).await?;
+            Ok(TokioIo::new(ProxyTunnel(stream)))
+        })
+    }
+}
+
+fn destination(uri: &Uri, scheme: &str) -> Result<DestinationAddress, ProxyStreamError> {
+    let host = uri.host().ok_or(AddrError::InvalidAddress)?;
+    let port = match uri.port_u16() {
+        Some(port) => port,
+        None if scheme.eq_ignore_ascii_case("https") || scheme.eq_ignore_ascii_case("wss") => 443,
+        None => 80,
+    };
+    Ok(match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).into(),
+        Err(_) => DestinationAddress::Domain(host.to_string(), port),
+    })
+}
+
+pub struct ProxyTunnel(Box<dyn AsyncSocket>);
+
+impl Connection for ProxyTunnel {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for ProxyTunnel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxyTunnel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.0.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl std::fmt::Debug for ProxyTunnel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProxyTunnel")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::{body::Bytes, server::conn::http1, service::service_fn, Request, Response};
+    use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{HttpConfig, Server, SocksConfig};
+
+    // An origin answering every request with its request target, which
+    // keeps the origin form only when the request came through a tunnel.
+    async fn origin() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let service = service_fn(|req: Request<_>| async move {
+                    let target = req.uri().to_string();
+                    Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(target))))
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(socket), service));
+            }
+        });
+        addr
+    }
+
+    async fn get_through(proxy: &str) -> String {
+        let origin = origin().await;
+        let spec: ProxySpec = proxy.parse().unwrap();
+        let connector = ProxyConnector::try_from(&spec).unwrap();
+        let client = Client::builder(TokioExecutor::new()).build::<_, Empty<Bytes>>(connector);
+        let uri = format!("http://{origin}/tunnelled").parse().unwrap();
+        let response = client.get(uri).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn tunnels_through_socks5() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::new(listener)
+                .serve_socks5_direct(SocksConfig::default(), std::future::pending()),
+        );
+        assert_eq!(
+            get_through(&format!("socks5://{proxy}")).await,
+            "/tunnelled"
+        );
+    }
+
+    #[tokio::test]
+    async fn tunnels_through_http_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::new(listener).serve_http_direct(HttpConfig::default(), std::future::pending()),
+        );
+        assert_eq!(get_through(&format!("http://{proxy}")).await, "/tunnelled");
+    }
+}
@@ -1,5 +1,6 @@
 use thiserror::Error;
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum AddrError {
     #[error("InvalidAddress")]
     InvalidAddress,
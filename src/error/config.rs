@@ -0,0 +1,33 @@
+use thiserror::Error;
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum ConfigError {
+    #[error("Method not provided")]
+    MethodNotProvided,
+    #[error("Too many methods provided")]
+    TooManyMethods,
+    #[error("Method not allowed: {0}")]
+    MethodNotAllowed(u8),
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+    #[error("Timeout must be greater than zero")]
+    ZeroTimeout,
+    #[error("Rate must be greater than zero")]
+    ZeroRate,
+    #[error("Buffer size must be greater than zero")]
+    ZeroBufferSize,
+    #[error("Invalid PEM data")]
+    InvalidPem,
+    #[error("Invalid TLS server name")]
+    InvalidServerName,
+    #[error("Invalid proxy URL")]
+    InvalidProxyUrl,
+    #[error("TLS requires the rustls feature")]
+    TlsUnsupported,
+    #[cfg(feature = "rustls")]
+    #[error("TLS")]
+    Tls(#[from] rustls::Error),
+    #[cfg(feature = "mitm")]
+    #[error("Certificate")]
+    Certificate(#[from] rcgen::Error),
+}
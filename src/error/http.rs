@@ -1,14 +1,33 @@
 use thiserror::Error;
+
+use super::ErrorClass;
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum HttpError {
     #[error("Unable to build HTTP request")]
-    BuildHttpReq,
+    BuildHttpReq(#[source] hyper::Error),
     #[error("Unable to create HTTP request")]
-    CreateHttpReq,
+    CreateHttpReq(#[source] hyper::http::Error),
     #[error("Unable to send HTTP request")]
-    SendHttpReq,
+    SendHttpReq(#[source] hyper::Error),
+    // The client's connection went away before its response was handed
+    // over; the undelivered response is all there is and is dropped.
     #[error("Unable to receive HTTP response")]
     SendHttpRes,
-    #[error("Unable to upgrade HTTP request: {0}")]
-    UpgradeHttpReq(#[from] hyper::Error),
+    #[error("Unable to upgrade HTTP request")]
+    UpgradeHttpReq(#[source] hyper::Error),
+    #[error("Unexpected HTTP status: {0}")]
+    UnexpectedStatus(hyper::StatusCode),
+}
+
+impl HttpError {
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            HttpError::BuildHttpReq(_)
+            | HttpError::SendHttpReq(_)
+            | HttpError::UnexpectedStatus(_) => ErrorClass::Upstream,
+            HttpError::SendHttpRes => ErrorClass::Client,
+            HttpError::CreateHttpReq(_) | HttpError::UpgradeHttpReq(_) => ErrorClass::Protocol,
+        }
+    }
 }
@@ -1,20 +1,144 @@
 use thiserror::Error;
-pub(crate) mod address;
-pub(crate) mod http;
-pub(crate) mod socks;
+
+use crate::{DestinationAddress, RelayOutcome, Side};
+pub mod address;
+pub mod config;
+pub mod http;
+pub mod socks;
+
+pub use address::AddrError;
+pub use config::ConfigError;
+pub use http::HttpError;
+pub use socks::SocksError;
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum ProxyStreamError {
-    #[error("AddressError: {0}")]
+    #[error("AddressError")]
     Address(#[from] address::AddrError),
-    #[error("HttpError: {0}")]
+    #[error("ConfigError")]
+    Config(#[from] config::ConfigError),
+    #[error("HttpError")]
     Http(#[from] http::HttpError),
-    #[error("SocksError: {0}")]
+    #[error("SocksError")]
     Socks(#[from] socks::SocksError),
-    #[error("IOError: {0}")]
+    #[error("IOError")]
     IO(#[from] std::io::Error),
+    #[error("Unable to connect to {addr}")]
+    Upstream {
+        addr: DestinationAddress,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Timeout: {0}")]
+    Timeout(TimeoutKind),
+    #[error("NotAllowed")]
+    NotAllowed,
+    #[error("QuotaExceeded")]
+    QuotaExceeded,
+    #[error("Relay closed: {:?}", .0.close_reason)]
+    Relay(RelayOutcome),
     #[error("NotImplemented")]
     NotImplemented,
     #[error("Closed")]
     Closed,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Handshake,
+    Idle,
+    Lifetime,
+}
+
+impl std::fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeoutKind::Handshake => write!(f, "Handshake"),
+            TimeoutKind::Idle => write!(f, "Idle"),
+            TimeoutKind::Lifetime => write!(f, "Lifetime"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    Config,
+    Client,
+    Upstream,
+    Protocol,
+}
+
+impl ProxyStreamError {
+    pub fn upstream(addr: &DestinationAddress, source: std::io::Error) -> Self {
+        ProxyStreamError::Upstream {
+            addr: addr.clone(),
+            source,
+        }
+    }
+
+    // I/O errors met while talking to the upstream at `addr` are its
+    // failure, not the client's.
+    pub(crate) fn at_upstream(self, addr: &DestinationAddress) -> Self {
+        match self {
+            ProxyStreamError::IO(e) | ProxyStreamError::Socks(SocksError::IOError(e)) => {
+                Self::upstream(addr, e)
+            }
+            e => e,
+        }
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            ProxyStreamError::IO(_)
+            | ProxyStreamError::Timeout(TimeoutKind::Handshake)
+            | ProxyStreamError::NotAllowed
+            | ProxyStreamError::QuotaExceeded
+            | ProxyStreamError::Closed => ErrorClass::Client,
+            // Idle and lifetime limits mostly end relays to stalled upstreams.
+            ProxyStreamError::Timeout(_) => ErrorClass::Upstream,
+            ProxyStreamError::Relay(outcome) => match outcome.failed {
+                Some(Side::Upstream) => ErrorClass::Upstream,
+                _ => ErrorClass::Client,
+            },
+            ProxyStreamError::Upstream { .. } => ErrorClass::Upstream,
+            ProxyStreamError::Config(_) => ErrorClass::Config,
+            ProxyStreamError::Http(e) => e.class(),
+            ProxyStreamError::Socks(e) => e.class(),
+            ProxyStreamError::Address(_) | ProxyStreamError::NotImplemented => ErrorClass::Protocol,
+        }
+    }
+
+    pub fn target(&self) -> Option<&DestinationAddress> {
+        match self {
+            ProxyStreamError::Upstream { addr, .. } => Some(addr),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CloseReason;
+
+    fn relay(failed: Option<Side>) -> ProxyStreamError {
+        ProxyStreamError::Relay(RelayOutcome {
+            bytes_up: 0,
+            bytes_down: 0,
+            close_reason: CloseReason::Reset,
+            failed,
+        })
+    }
+
+    #[test]
+    fn classifies_timeouts_and_relays() {
+        let timeout = |kind| ProxyStreamError::Timeout(kind).class();
+        assert_eq!(timeout(TimeoutKind::Handshake), ErrorClass::Client);
+        assert_eq!(timeout(TimeoutKind::Idle), ErrorClass::Upstream);
+        assert_eq!(timeout(TimeoutKind::Lifetime), ErrorClass::Upstream);
+        assert_eq!(relay(Some(Side::Upstream)).class(), ErrorClass::Upstream);
+        assert_eq!(relay(Some(Side::Client)).class(), ErrorClass::Client);
+        assert_eq!(relay(None).class(), ErrorClass::Client);
+    }
+}
@@ -1,7 +1,8 @@
 use thiserror::Error;
 
-use super::address;
+use super::{address, ErrorClass};
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum SocksError {
     #[error("Invalid Version")]
     InvalidVersion,
@@ -15,8 +16,22 @@ pub enum SocksError {
     TooManyMethods,
     #[error("Invalid Address")]
     InvalidAddress,
-    #[error("IOError: {0}")]
+    #[error("Authentication failed")]
+    AuthenticationFailed,
+    #[error("Command failed: {0:?}")]
+    CommandFailed(crate::ReplayStatus),
+    #[error("IOError")]
     IOError(#[from] std::io::Error),
-    #[error("AddressError: {0}")]
+    #[error("AddressError")]
     AddressError(#[from] address::AddrError),
 }
+
+impl SocksError {
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            SocksError::IOError(_) => ErrorClass::Client,
+            SocksError::CommandFailed(_) => ErrorClass::Upstream,
+            _ => ErrorClass::Protocol,
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Empty, Full};
+use hyper::body::{Body, Bytes, Frame, Incoming, SizeHint};
+use tokio::sync::oneshot;
+
+use crate::record::Counters;
+
+pub(crate) type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// Body of forwarded requests and responses; either what the peer sent or
+// whatever the application replaced it with. The mutex keeps it `Sync`
+// without asking that of every body; polling never locks it.
+pub struct HttpBody(Mutex<UnsyncBoxBody<Bytes, BoxError>>);
+
+impl HttpBody {
+    pub fn new<B>(body: B) -> Self
+    where
+        B: Body<Data = Bytes> + Send + 'static,
+        B::Error: Into<BoxError>,
+    {
+        HttpBody(Mutex::new(body.map_err(Into::into).boxed_unsync()))
+    }
+
+    pub fn empty() -> Self {
+        Self::new(Empty::new())
+    }
+}
+
+impl Default for HttpBody {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl From<Incoming> for HttpBody {
+    fn from(body: Incoming) -> Self {
+        Self::new(body)
+    }
+}
+
+impl From<Bytes> for HttpBody {
+    fn from(bytes: Bytes) -> Self {
+        Self::new(Full::new(bytes))
+    }
+}
+
+impl From<Vec<u8>> for HttpBody {
+    fn from(bytes: Vec<u8>) -> Self {
+        Bytes::from(bytes).into()
+    }
+}
+
+impl From<String> for HttpBody {
+    fn from(text: String) -> Self {
+        Bytes::from(text).into()
+    }
+}
+
+impl From<&'static str> for HttpBody {
+    fn from(text: &'static str) -> Self {
+        Bytes::from_static(text.as_bytes()).into()
+    }
+}
+
+impl Body for HttpBody {
+    type Data = Bytes;
+
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let body = self.0.get_mut().unwrap_or_else(|e| e.into_inner());
+        Pin::new(body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).size_hint()
+    }
+}
+
+// Counts the data sent to the client as written and, once dropped, tells
+// `done` whether it got to the end.
+pub(crate) struct Metered {
+    body: HttpBody,
+    counters: Arc<Counters>,
+    ended: bool,
+    done: Option<oneshot::Sender<bool>>,
+}
+
+impl Metered {
+    pub(crate) fn new(
+        body: HttpBody,
+        counters: Arc<Counters>,
+        done: oneshot::Sender<bool>,
+    ) -> Self {
+        Metered {
+            body,
+            counters,
+            ended: false,
+            done: Some(done),
+        }
+    }
+}
+
+impl Body for Metered {
+    type Data = Bytes;
+
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.body).poll_frame(cx);
+        match &frame {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self.counters.add_written(data.len());
+                }
+            }
+            Poll::Ready(None) => self.ended = true,
+            _ => {}
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for Metered {
+    fn drop(&mut self) {
+        let ended = self.ended || self.body.is_end_stream();
+        if let Some(done) = self.done.take() {
+            let _ = done.send(ended);
+        }
+    }
+}
+
+impl fmt::Debug for HttpBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HttpBody")
+    }
+}
@@ -0,0 +1,1143 @@
+// Shared HTTP cache for plain (and intercepted) forwarded requests, following
+// RFC 9111. Only GET responses are stored, one variant per URL.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use http_body_util::Full;
+use hyper::{
+    body::{Body, Bytes, Frame, SizeHint},
+    header::{
+        HeaderMap, HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONNECTION,
+        CONTENT_LENGTH, DATE, ETAG, EXPIRES, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE,
+        IF_UNMODIFIED_SINCE, LAST_MODIFIED, PRAGMA, SET_COOKIE, TE, TRAILER, TRANSFER_ENCODING,
+        UPGRADE, VARY,
+    },
+    Method, Request, Response, StatusCode,
+};
+
+use super::{body::BoxError, HttpBody};
+use crate::DestinationAddress;
+
+const DEFAULT_MAX_ENTRY_SIZE: u64 = 16 * 1024 * 1024;
+
+// Upper bound for freshness guessed from Last-Modified (RFC 9111 §4.2.2).
+const MAX_HEURISTIC_FRESHNESS: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone)]
+pub struct HttpCache {
+    inner: Arc<Inner>,
+    max_entry_size: u64,
+}
+
+struct Inner {
+    store: Store,
+    max_size: u64,
+    index: Mutex<Index>,
+    writes: AtomicU64,
+}
+
+enum Store {
+    Memory,
+    Disk(PathBuf),
+}
+
+// Least recently used first in `order`.
+#[derive(Default)]
+struct Index {
+    slots: HashMap<String, Slot>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    size: u64,
+}
+
+struct Slot {
+    tick: u64,
+    size: u64,
+    // Kept here by the memory store, read back from the file by the disk one.
+    entry: Option<Arc<Entry>>,
+}
+
+impl HttpCache {
+    // Keeps up to `max_size` bytes of responses in memory.
+    pub fn memory(max_size: u64) -> Self {
+        Self::new(Store::Memory, max_size, Index::default())
+    }
+
+    // Keeps up to `max_size` bytes of responses as files in `dir`, picking up
+    // whatever an earlier process left there.
+    pub fn disk(dir: impl Into<PathBuf>, max_size: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        let mut files = Vec::new();
+        for file in std::fs::read_dir(&dir)? {
+            let path = file?.path();
+            if path.extension().is_some_and(|e| e == "tmp") {
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            let Ok(metadata) = std::fs::metadata(&path) else {
+                continue;
+            };
+            // A file not named after its key could never be looked up.
+            match read_key(&path).filter(|key| path == file_path(&dir, key)) {
+                Some(key) => files.push((metadata.modified().ok(), key, metadata.len())),
+                None => {
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+        files.sort_by_key(|(modified, ..)| *modified);
+        let mut index = Index::default();
+        for (_, key, size) in files {
+            index.insert(key, size, None);
+        }
+        let cache = Self::new(Store::Disk(dir), max_size, index);
+        let evicted = cache.index().evict(max_size);
+        cache.remove_files(evicted);
+        Ok(cache)
+    }
+
+    fn new(store: Store, max_size: u64, index: Index) -> Self {
+        HttpCache {
+            inner: Arc::new(Inner {
+                store,
+                max_size,
+                index: Mutex::new(index),
+                writes: AtomicU64::new(0),
+            }),
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+        }
+    }
+
+    // Larger responses are relayed without being stored.
+    pub fn max_entry_size(mut self, size: u64) -> Self {
+        self.max_entry_size = size;
+        self
+    }
+
+    pub fn size(&self) -> u64 {
+        self.index().size
+    }
+
+    pub(crate) async fn lookup<B>(
+        &self,
+        req: &mut Request<B>,
+        addr: &DestinationAddress,
+        tls: bool,
+    ) -> Lookup {
+        let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+        let scheme = if tls { "https" } else { "http" };
+        let mut request = CacheRequest {
+            key: format!("{}://{}{}", scheme, addr, path),
+            method: req.method().clone(),
+            headers: req.headers().clone(),
+            request_time: SystemTime::now(),
+            revalidating: None,
+        };
+        let directives = CacheControl::parse(req.headers());
+        if req.method() != Method::GET || directives.no_store {
+            return Lookup::Forward(request);
+        }
+        let entry = self
+            .get(&request.key)
+            .await
+            .filter(|entry| entry.varies_with(req.headers()));
+        let Some(entry) = entry else {
+            return match directives.only_if_cached {
+                true => Lookup::Answer(gateway_timeout()),
+                false => Lookup::Forward(request),
+            };
+        };
+        let age = entry.age(SystemTime::now());
+        let conditional = [IF_MATCH, IF_UNMODIFIED_SINCE, IF_RANGE]
+            .iter()
+            .any(|name| req.headers().contains_key(name));
+        if !conditional && entry.satisfies(age, &directives) {
+            return Lookup::Answer(entry.response(age, req.headers()));
+        }
+        if directives.only_if_cached {
+            return Lookup::Answer(gateway_timeout());
+        }
+        // The client's own validators are left alone, and so is its 304.
+        let validating = [IF_NONE_MATCH, IF_MODIFIED_SINCE]
+            .iter()
+            .any(|name| req.headers().contains_key(name));
+        if !conditional && !validating {
+            let headers = req.headers_mut();
+            if let Some(etag) = entry.headers.get(ETAG) {
+                headers.insert(IF_NONE_MATCH, etag.clone());
+            }
+            if let Some(modified) = entry.headers.get(LAST_MODIFIED) {
+                headers.insert(IF_MODIFIED_SINCE, modified.clone());
+            }
+            if headers.contains_key(IF_NONE_MATCH) || headers.contains_key(IF_MODIFIED_SINCE) {
+                request.revalidating = Some(entry);
+            }
+        }
+        Lookup::Forward(request)
+    }
+
+    // Stores what can be stored as it streams through, and turns the answer
+    // to a revalidation back into the stored response.
+    pub(crate) async fn store(
+        &self,
+        request: CacheRequest,
+        res: Response<HttpBody>,
+    ) -> Response<HttpBody> {
+        let response_time = SystemTime::now();
+        let status = res.status();
+        if !request.method.is_safe() {
+            // RFC 9111 §4.4: a successful unsafe request invalidates the URL.
+            if !status.is_client_error() && !status.is_server_error() {
+                self.remove(&request.key).await;
+            }
+            return res;
+        }
+        if status == StatusCode::NOT_MODIFIED {
+            let Some(entry) = request.revalidating else {
+                return res;
+            };
+            let entry = entry.freshen(res.headers(), request.request_time, response_time);
+            let age = entry.age(SystemTime::now());
+            let response = entry.response(age, &request.headers);
+            self.insert(request.key, entry).await;
+            return response;
+        }
+        let Some(vary) = storable(&request, &res) else {
+            return res;
+        };
+        let (parts, body) = res.into_parts();
+        let entry = Entry {
+            status,
+            headers: stored_headers(&parts.headers, response_time),
+            body: Bytes::new(),
+            vary,
+            request_time: request.request_time,
+            response_time,
+        };
+        if body.is_end_stream() {
+            self.insert(request.key, entry).await;
+            return Response::from_parts(parts, body);
+        }
+        let limit = self.max_entry_size.min(self.inner.max_size);
+        let body = Tee {
+            body,
+            buf: Vec::new(),
+            limit,
+            pending: Some((self.clone(), request.key, entry)),
+        };
+        Response::from_parts(parts, HttpBody::new(body))
+    }
+
+    async fn get(&self, key: &str) -> Option<Arc<Entry>> {
+        let found = self.index().touch(key)?;
+        let Store::Disk(dir) = &self.inner.store else {
+            return found;
+        };
+        let entry = tokio::fs::read(file_path(dir, key))
+            .await
+            .ok()
+            .and_then(|bytes| Entry::decode(&bytes))
+            .filter(|(stored, _)| stored == key);
+        match entry {
+            Some((_, entry)) => Some(Arc::new(entry)),
+            None => {
+                self.index().remove(key);
+                None
+            }
+        }
+    }
+
+    async fn insert(&self, key: String, entry: Entry) {
+        let size = entry.size();
+        if size > self.inner.max_size {
+            return;
+        }
+        let entry = match &self.inner.store {
+            Store::Memory => Some(Arc::new(entry)),
+            Store::Disk(dir) => {
+                let path = file_path(dir, &key);
+                let write = self.inner.writes.fetch_add(1, Ordering::Relaxed);
+                let tmp = path.with_extension(format!("{}.tmp", write));
+                if tokio::fs::write(&tmp, entry.encode(&key)).await.is_err()
+                    || tokio::fs::rename(&tmp, &path).await.is_err()
+                {
+                    let _ = tokio::fs::remove_file(&tmp).await;
+                    return;
+                }
+                None
+            }
+        };
+        let evicted = {
+            let mut index = self.index();
+            index.insert(key, size, entry);
+            index.evict(self.inner.max_size)
+        };
+        self.remove_files(evicted);
+    }
+
+    async fn remove(&self, key: &str) {
+        if self.index().remove(key) {
+            self.remove_files(vec![key.to_string()]);
+        }
+    }
+
+    fn remove_files(&self, keys: Vec<String>) {
+        if let Store::Disk(dir) = &self.inner.store {
+            for key in keys {
+                let _ = std::fs::remove_file(file_path(dir, &key));
+            }
+        }
+    }
+
+    fn index(&self) -> MutexGuard<'_, Index> {
+        self.inner.index.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for HttpCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let store = match &self.inner.store {
+            Store::Memory => None,
+            Store::Disk(dir) => Some(dir),
+        };
+        f.debug_struct("HttpCache")
+            .field("dir", &store)
+            .field("max_size", &self.inner.max_size)
+            .field("max_entry_size", &self.max_entry_size)
+            .finish()
+    }
+}
+
+impl Index {
+    fn touch(&mut self, key: &str) -> Option<Option<Arc<Entry>>> {
+        self.tick += 1;
+        let slot = self.slots.get_mut(key)?;
+        let key = self.order.remove(&slot.tick)?;
+        slot.tick = self.tick;
+        self.order.insert(self.tick, key);
+        Some(slot.entry.clone())
+    }
+
+    fn insert(&mut self, key: String, size: u64, entry: Option<Arc<Entry>>) {
+        self.remove(&key);
+        self.tick += 1;
+        self.size += size;
+        self.order.insert(self.tick, key.clone());
+        let tick = self.tick;
+        self.slots.insert(key, Slot { tick, size, entry });
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        let Some(slot) = self.slots.remove(key) else {
+            return false;
+        };
+        self.order.remove(&slot.tick);
+        self.size -= slot.size;
+        true
+    }
+
+    fn evict(&mut self, max_size: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.size > max_size {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(slot) = self.slots.remove(&key) {
+                self.size -= slot.size;
+            }
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+pub(crate) enum Lookup {
+    // Served without contacting the origin.
+    Answer(Response<HttpBody>),
+    Forward(CacheRequest),
+}
+
+// What the cache needs to know about a request once its response arrives.
+pub(crate) struct CacheRequest {
+    key: String,
+    method: Method,
+    headers: HeaderMap,
+    request_time: SystemTime,
+    revalidating: Option<Arc<Entry>>,
+}
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    // Request headers the response varies on, as they were when stored.
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    request_time: SystemTime,
+    response_time: SystemTime,
+}
+
+impl Entry {
+    fn varies_with(&self, headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| headers.get(name) == value.as_ref())
+    }
+
+    // RFC 9111 §4.2.3.
+    fn age(&self, now: SystemTime) -> Duration {
+        let date = http_date(&self.headers, DATE).unwrap_or(self.response_time);
+        let apparent_age = elapsed(date, self.response_time);
+        let age_value = self
+            .headers
+            .get(AGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        let response_delay = elapsed(self.request_time, self.response_time);
+        let initial_age = apparent_age.max(age_value + response_delay);
+        initial_age + elapsed(self.response_time, now)
+    }
+
+    // RFC 9111 §4.2.1, as a shared cache.
+    fn freshness_lifetime(&self, directives: &CacheControl) -> Duration {
+        if let Some(secs) = directives.s_maxage.or(directives.max_age) {
+            return Duration::from_secs(secs);
+        }
+        let date = http_date(&self.headers, DATE).unwrap_or(self.response_time);
+        if self.headers.contains_key(EXPIRES) {
+            return http_date(&self.headers, EXPIRES)
+                .map(|expires| elapsed(date, expires))
+                .unwrap_or_default();
+        }
+        match http_date(&self.headers, LAST_MODIFIED) {
+            Some(modified) if heuristically_cacheable(self.status) => {
+                (elapsed(modified, date) / 10).min(MAX_HEURISTIC_FRESHNESS)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    fn satisfies(&self, age: Duration, request: &CacheControl) -> bool {
+        let response = CacheControl::parse(&self.headers);
+        if request.no_cache || response.no_cache {
+            return false;
+        }
+        if request
+            .max_age
+            .is_some_and(|max| age > Duration::from_secs(max))
+        {
+            return false;
+        }
+        let lifetime = self.freshness_lifetime(&response);
+        let min_fresh = Duration::from_secs(request.min_fresh.unwrap_or(0));
+        if age + min_fresh < lifetime {
+            return true;
+        }
+        // Stale, which the client may accept unless the origin forbids it.
+        match request.max_stale {
+            Some(max_stale) if !response.must_revalidate && age >= lifetime => {
+                max_stale.is_none_or(|max| age - lifetime <= Duration::from_secs(max))
+            }
+            _ => false,
+        }
+    }
+
+    fn response(&self, age: Duration, request: &HeaderMap) -> Response<HttpBody> {
+        let mut headers = self.headers.clone();
+        headers.insert(AGE, HeaderValue::from(age.as_secs()));
+        if self.not_modified_for(request) {
+            let mut res = Response::new(HttpBody::empty());
+            *res.status_mut() = StatusCode::NOT_MODIFIED;
+            *res.headers_mut() = headers;
+            return res;
+        }
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(self.body.len()));
+        let mut res = Response::new(HttpBody::new(Full::new(self.body.clone())));
+        *res.status_mut() = self.status;
+        *res.headers_mut() = headers;
+        res
+    }
+
+    // RFC 9110 §13.1.1 and §13.1.3, for a client revalidating its own copy.
+    fn not_modified_for(&self, request: &HeaderMap) -> bool {
+        if self.status != StatusCode::OK {
+            return false;
+        }
+        if let Some(tags) = request.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+            let Some(etag) = self.headers.get(ETAG).and_then(|v| v.to_str().ok()) else {
+                return false;
+            };
+            let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+            return tags
+                .split(',')
+                .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag));
+        }
+        match (
+            http_date(request, IF_MODIFIED_SINCE),
+            http_date(&self.headers, LAST_MODIFIED),
+        ) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    // RFC 9111 §4.3.4: headers of the 304 replace the stored ones.
+    fn freshen(
+        &self,
+        headers: &HeaderMap,
+        request_time: SystemTime,
+        response_time: SystemTime,
+    ) -> Entry {
+        let mut stored = self.headers.clone();
+        for name in stored_headers(headers, response_time).keys() {
+            stored.remove(name);
+        }
+        for (name, value) in stored_headers(headers, response_time).iter() {
+            stored.append(name, value.clone());
+        }
+        Entry {
+            status: self.status,
+            headers: stored,
+            body: self.body.clone(),
+            vary: self.vary.clone(),
+            request_time,
+            response_time,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        (self.body.len() + headers) as u64
+    }
+
+    // Key, status, times and vary lines, then the headers, a blank line and
+    // the body.
+    fn encode(&self, key: &str) -> Vec<u8> {
+        let secs = |time: SystemTime| elapsed(SystemTime::UNIX_EPOCH, time).as_secs();
+        let mut out = format!(
+            "{}\n{}\n{} {}\n{}\n",
+            key,
+            self.status.as_u16(),
+            secs(self.request_time),
+            secs(self.response_time),
+            self.vary.len()
+        )
+        .into_bytes();
+        for (name, value) in &self.vary {
+            out.extend_from_slice(name.as_str().as_bytes());
+            if let Some(value) = value {
+                out.push(b':');
+                out.extend_from_slice(value.as_bytes());
+            }
+            out.push(b'\n');
+        }
+        for (name, value) in &self.headers {
+            out.extend_from_slice(name.as_str().as_bytes());
+            out.push(b':');
+            out.extend_from_slice(value.as_bytes());
+            out.push(b'\n');
+        }
+        out.push(b'\n');
+        out.extend_from_slice(&self.body);
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<(String, Entry)> {
+        let mut rest = bytes;
+        let mut line = || {
+            let end = rest.iter().position(|b| *b == b'\n')?;
+            let line = &rest[..end];
+            rest = &rest[end + 1..];
+            Some(line)
+        };
+        let key = String::from_utf8(line()?.to_vec()).ok()?;
+        let status = StatusCode::from_bytes(line()?).ok()?;
+        let times = std::str::from_utf8(line()?).ok()?;
+        let (request_time, response_time) = times.split_once(' ')?;
+        let time =
+            |secs: &str| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs.parse().ok()?));
+        let (request_time, response_time) = (time(request_time)?, time(response_time)?);
+        let vary_count: usize = std::str::from_utf8(line()?).ok()?.parse().ok()?;
+        let mut vary = Vec::with_capacity(vary_count);
+        for _ in 0..vary_count {
+            let line = line()?;
+            let (name, value) = match line.iter().position(|b| *b == b':') {
+                Some(colon) => (&line[..colon], Some(&line[colon + 1..])),
+                None => (line, None),
+            };
+            let value = match value {
+                Some(value) => Some(HeaderValue::from_bytes(value).ok()?),
+                None => None,
+            };
+            vary.push((HeaderName::from_bytes(name).ok()?, value));
+        }
+        let mut headers = HeaderMap::new();
+        loop {
+            let line = line()?;
+            if line.is_empty() {
+                break;
+            }
+            let colon = line.iter().position(|b| *b == b':')?;
+            headers.append(
+                HeaderName::from_bytes(&line[..colon]).ok()?,
+                HeaderValue::from_bytes(&line[colon + 1..]).ok()?,
+            );
+        }
+        let entry = Entry {
+            status,
+            headers,
+            body: Bytes::copy_from_slice(rest),
+            vary,
+            request_time,
+            response_time,
+        };
+        Some((key, entry))
+    }
+}
+
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    must_revalidate: bool,
+    only_if_cached: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+    min_fresh: Option<u64>,
+    // `max-stale` without a value accepts any staleness.
+    max_stale: Option<Option<u64>>,
+}
+
+impl CacheControl {
+    // Qualified `private` and `no-cache` are treated as their unqualified
+    // forms, which errs on the side of not serving from the cache.
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut directives = CacheControl::default();
+        let mut found = false;
+        let values = headers.get_all(CACHE_CONTROL);
+        for directive in values
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+        {
+            let (name, arg) = match directive.split_once('=') {
+                Some((name, arg)) => (name.trim(), Some(arg.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let secs = arg.and_then(|arg| arg.parse().ok());
+            found = true;
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "public" => directives.public = true,
+                "must-revalidate" | "proxy-revalidate" => directives.must_revalidate = true,
+                "only-if-cached" => directives.only_if_cached = true,
+                // An invalid age makes a response stale (RFC 9111 §1.2.2).
+                "max-age" => directives.max_age = Some(secs.unwrap_or(0)),
+                "s-maxage" => directives.s_maxage = Some(secs.unwrap_or(0)),
+                "min-fresh" => directives.min_fresh = secs,
+                "max-stale" => directives.max_stale = Some(secs),
+                _ => {}
+            }
+        }
+        // HTTP/1.0 clients only know Pragma.
+        let pragma = headers
+            .get_all(PRAGMA)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.to_ascii_lowercase().contains("no-cache"));
+        if !found && pragma {
+            directives.no_cache = true;
+        }
+        directives
+    }
+}
+
+// Collects the body into the cache while passing it on; anything cut short
+// or too large is not stored.
+struct Tee {
+    body: HttpBody,
+    buf: Vec<u8>,
+    limit: u64,
+    pending: Option<(HttpCache, String, Entry)>,
+}
+
+impl Tee {
+    fn finish(&mut self) {
+        if let Some((cache, key, mut entry)) = self.pending.take() {
+            entry.body = std::mem::take(&mut self.buf).into();
+            tokio::spawn(async move { cache.insert(key, entry).await });
+        }
+    }
+}
+
+impl Body for Tee {
+    type Data = Bytes;
+
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let frame = ready!(Pin::new(&mut this.body).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    if (this.buf.len() + data.len()) as u64 > this.limit {
+                        this.pending = None;
+                        this.buf = Vec::new();
+                    } else if this.pending.is_some() {
+                        this.buf.extend_from_slice(data);
+                    }
+                }
+            }
+            Some(Err(_)) => this.pending = None,
+            None => this.finish(),
+        }
+        // Readers stop polling once the body says it has ended.
+        if this.body.is_end_stream() {
+            this.finish();
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+// RFC 9111 §3; returns the request headers the response varies on.
+fn storable(
+    request: &CacheRequest,
+    res: &Response<HttpBody>,
+) -> Option<Vec<(HeaderName, Option<HeaderValue>)>> {
+    let requested = CacheControl::parse(&request.headers);
+    let directives = CacheControl::parse(res.headers());
+    if request.method != Method::GET
+        || requested.no_store
+        || directives.no_store
+        || directives.private
+        || res.status() == StatusCode::PARTIAL_CONTENT
+    {
+        return None;
+    }
+    // A shared cache would hand one user's cookies to the next.
+    if res.headers().contains_key(SET_COOKIE) {
+        return None;
+    }
+    if request.headers.contains_key(AUTHORIZATION)
+        && !(directives.public || directives.s_maxage.is_some() || directives.must_revalidate)
+    {
+        return None;
+    }
+    let explicit = directives.public
+        || directives.max_age.is_some()
+        || directives.s_maxage.is_some()
+        || res.headers().contains_key(EXPIRES);
+    if !explicit && !heuristically_cacheable(res.status()) {
+        return None;
+    }
+    let mut vary = Vec::new();
+    for name in res
+        .headers()
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        if name == "*" {
+            return None;
+        }
+        let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+        let value = request.headers.get(&name).cloned();
+        vary.push((name, value));
+    }
+    Some(vary)
+}
+
+// RFC 9110 §15.1.
+fn heuristically_cacheable(status: StatusCode) -> bool {
+    matches!(
+        status.as_u16(),
+        200 | 203 | 204 | 300 | 301 | 308 | 404 | 405 | 410 | 414 | 501
+    )
+}
+
+// Drops hop-by-hop headers and the framing, which is recomputed on the way
+// out, and dates responses that came without a Date.
+fn stored_headers(headers: &HeaderMap, response_time: SystemTime) -> HeaderMap {
+    let mut stored = headers.clone();
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+    for name in listed {
+        stored.remove(name);
+    }
+    for name in [
+        CONNECTION,
+        CONTENT_LENGTH,
+        TE,
+        TRAILER,
+        TRANSFER_ENCODING,
+        UPGRADE,
+        HeaderName::from_static("keep-alive"),
+        HeaderName::from_static("proxy-connection"),
+    ] {
+        stored.remove(name);
+    }
+    if !stored.contains_key(DATE) {
+        if let Ok(date) = HeaderValue::from_str(&httpdate::fmt_http_date(response_time)) {
+            stored.insert(DATE, date);
+        }
+    }
+    stored
+}
+
+fn gateway_timeout() -> Response<HttpBody> {
+    let mut res = Response::new(HttpBody::empty());
+    *res.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+    res
+}
+
+fn http_date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    let value = headers.get(name)?.to_str().ok()?;
+    httpdate::parse_http_date(value).ok()
+}
+
+fn elapsed(from: SystemTime, to: SystemTime) -> Duration {
+    to.duration_since(from).unwrap_or_default()
+}
+
+// File names must stay the same across builds, so this is FNV-1a rather
+// than the std hasher.
+fn file_path(dir: &Path, key: &str) -> PathBuf {
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    dir.join(format!("{hash:016x}"))
+}
+
+fn read_key(path: &Path) -> Option<String> {
+    use std::io::BufRead;
+
+    let mut line = String::new();
+    let file = std::fs::File::open(path).ok()?;
+    io::BufReader::new(file).read_line(&mut line).ok()?;
+    line.strip_suffix('\n').map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    fn base() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    fn date(time: SystemTime) -> String {
+        httpdate::fmt_http_date(time)
+    }
+
+    fn entry(pairs: &[(&str, &str)], response_time: SystemTime) -> Entry {
+        Entry {
+            status: StatusCode::OK,
+            headers: headers(pairs),
+            body: Bytes::from_static(b"stored"),
+            vary: Vec::new(),
+            request_time: response_time,
+            response_time,
+        }
+    }
+
+    fn request(uri: &str, pairs: &[(&str, &str)]) -> Request<HttpBody> {
+        let mut req = Request::new(HttpBody::empty());
+        *req.uri_mut() = uri.parse().unwrap();
+        *req.headers_mut() = headers(pairs);
+        req
+    }
+
+    async fn body(res: Response<HttpBody>) -> Bytes {
+        res.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[test]
+    fn parses_cache_control() {
+        let directives = CacheControl::parse(&headers(&[
+            ("cache-control", "Max-Age=60, s-maxage=\"30\", no-cache"),
+            (
+                "cache-control",
+                "private=\"set-cookie\", max-stale, min-fresh=5",
+            ),
+        ]));
+        assert_eq!(directives.max_age, Some(60));
+        assert_eq!(directives.s_maxage, Some(30));
+        assert!(directives.no_cache && directives.private);
+        assert_eq!(directives.max_stale, Some(None));
+        assert_eq!(directives.min_fresh, Some(5));
+        assert!(!directives.no_store && !directives.public);
+
+        let directives = CacheControl::parse(&headers(&[("cache-control", "max-age=soon")]));
+        assert_eq!(directives.max_age, Some(0));
+        let directives = CacheControl::parse(&headers(&[("cache-control", "max-stale=10")]));
+        assert_eq!(directives.max_stale, Some(Some(10)));
+
+        assert!(CacheControl::parse(&headers(&[("pragma", "no-cache")])).no_cache);
+        let directives = CacheControl::parse(&headers(&[
+            ("pragma", "no-cache"),
+            ("cache-control", "max-age=5"),
+        ]));
+        assert!(!directives.no_cache);
+    }
+
+    #[test]
+    fn computes_age() {
+        let response_time = base();
+        let mut stored = entry(
+            &[
+                ("date", &date(response_time - Duration::from_secs(10))),
+                ("age", "5"),
+            ],
+            response_time,
+        );
+        stored.request_time = response_time - Duration::from_secs(2);
+        let now = response_time + Duration::from_secs(3);
+        assert_eq!(stored.age(now), Duration::from_secs(13));
+
+        // A large Age plus the response delay outweighs the apparent age.
+        stored.headers.insert(AGE, HeaderValue::from(20));
+        assert_eq!(stored.age(now), Duration::from_secs(25));
+
+        let stored = entry(&[], response_time);
+        assert_eq!(stored.age(response_time), Duration::ZERO);
+    }
+
+    #[test]
+    fn computes_freshness_lifetime() {
+        let now = base();
+        let lifetime = |pairs: &[(&str, &str)]| {
+            let stored = entry(pairs, now);
+            stored.freshness_lifetime(&CacheControl::parse(&stored.headers))
+        };
+        let secs = Duration::from_secs;
+        assert_eq!(
+            lifetime(&[("cache-control", "max-age=60, s-maxage=30")]),
+            secs(30)
+        );
+        assert_eq!(
+            lifetime(&[
+                ("cache-control", "max-age=60"),
+                ("expires", &date(now + secs(600)))
+            ]),
+            secs(60)
+        );
+        assert_eq!(
+            lifetime(&[("date", &date(now)), ("expires", &date(now + secs(600)))]),
+            secs(600)
+        );
+        assert_eq!(lifetime(&[("expires", "0")]), Duration::ZERO);
+        assert_eq!(
+            lifetime(&[
+                ("date", &date(now)),
+                ("last-modified", &date(now - secs(1000)))
+            ]),
+            secs(100)
+        );
+        assert_eq!(
+            lifetime(&[("last-modified", &date(now - secs(100 * 24 * 60 * 60)))]),
+            MAX_HEURISTIC_FRESHNESS
+        );
+        assert_eq!(lifetime(&[]), Duration::ZERO);
+
+        let mut stored = entry(&[("last-modified", &date(now - secs(1000)))], now);
+        stored.status = StatusCode::FOUND;
+        assert_eq!(
+            stored.freshness_lifetime(&CacheControl::default()),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn checks_request_directives() {
+        let secs = Duration::from_secs;
+        let satisfies = |response: &str, age: u64, request: &str| {
+            let stored = entry(&[("cache-control", response)], base());
+            let request = CacheControl::parse(&headers(&[("cache-control", request)]));
+            stored.satisfies(secs(age), &request)
+        };
+        assert!(satisfies("max-age=60", 10, ""));
+        assert!(!satisfies("max-age=60", 60, ""));
+        assert!(!satisfies("max-age=60, no-cache", 10, ""));
+        assert!(!satisfies("max-age=60", 10, "no-cache"));
+        assert!(!satisfies("max-age=60", 10, "max-age=5"));
+        assert!(satisfies("max-age=60", 10, "min-fresh=40"));
+        assert!(!satisfies("max-age=60", 10, "min-fresh=50"));
+        assert!(satisfies("max-age=60", 70, "max-stale"));
+        assert!(satisfies("max-age=60", 70, "max-stale=10"));
+        assert!(!satisfies("max-age=60", 71, "max-stale=10"));
+        assert!(!satisfies("max-age=60, must-revalidate", 70, "max-stale"));
+    }
+
+    #[test]
+    fn round_trips_entries() {
+        let mut stored = entry(
+            &[
+                ("cache-control", "max-age=60"),
+                ("x-multi", "one"),
+                ("x-multi", "two: with colon"),
+            ],
+            base(),
+        );
+        stored.status = StatusCode::NOT_FOUND;
+        stored.request_time = base() - Duration::from_secs(1);
+        stored.body = Bytes::from_static(b"line\n\nbody:\0");
+        stored.vary = vec![
+            (
+                HeaderName::from_static("accept-language"),
+                Some(HeaderValue::from_static("en")),
+            ),
+            (HeaderName::from_static("accept-encoding"), None),
+        ];
+        let key = "http://example.com:80/path?q";
+        let (decoded_key, decoded) = Entry::decode(&stored.encode(key)).unwrap();
+        assert_eq!(decoded_key, key);
+        assert_eq!(decoded.status, stored.status);
+        assert_eq!(decoded.headers, stored.headers);
+        assert_eq!(decoded.body, stored.body);
+        assert_eq!(decoded.vary, stored.vary);
+        assert_eq!(decoded.request_time, stored.request_time);
+        assert_eq!(decoded.response_time, stored.response_time);
+
+        let encoded = stored.encode(key);
+        assert!(Entry::decode(&encoded[..key.len() + 3]).is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut index = Index::default();
+        for key in ["a", "b", "c"] {
+            index.insert(key.to_string(), 10, None);
+        }
+        assert!(index.touch("a").is_some());
+        assert!(index.touch("missing").is_none());
+        assert_eq!(index.evict(30), Vec::<String>::new());
+        assert_eq!(index.evict(20), vec!["b".to_string()]);
+        assert_eq!(index.size, 20);
+
+        // Replacing a key accounts for its new size only.
+        index.insert("c".to_string(), 25, None);
+        assert_eq!(index.size, 35);
+        assert_eq!(index.evict(30), vec!["a".to_string()]);
+        assert!(index.remove("c"));
+        assert!(!index.remove("c"));
+        assert_eq!(index.size, 0);
+    }
+
+    #[tokio::test]
+    async fn forwards_on_vary_mismatch() {
+        let cache = HttpCache::memory(1 << 20);
+        let addr = DestinationAddress::Domain("example.com".to_string(), 80);
+        let mut stored = entry(&[("cache-control", "max-age=60")], SystemTime::now());
+        stored.vary = vec![(
+            HeaderName::from_static("accept-language"),
+            Some(HeaderValue::from_static("en")),
+        )];
+        cache
+            .insert("http://example.com:80/page".to_string(), stored)
+            .await;
+
+        let mut req = request("/page", &[("accept-language", "de")]);
+        match cache.lookup(&mut req, &addr, false).await {
+            Lookup::Forward(request) => assert!(request.revalidating.is_none()),
+            Lookup::Answer(_) => panic!("answered a different variant"),
+        }
+        let mut req = request("/page", &[("accept-language", "en")]);
+        match cache.lookup(&mut req, &addr, false).await {
+            Lookup::Answer(res) => assert_eq!(body(res).await, "stored"),
+            Lookup::Forward(_) => panic!("missed a matching variant"),
+        }
+    }
+
+    #[tokio::test]
+    async fn freshens_on_not_modified() {
+        let cache = HttpCache::memory(1 << 20);
+        let addr = DestinationAddress::Domain("example.com".to_string(), 80);
+        let stored = entry(
+            &[("cache-control", "no-cache"), ("etag", "\"v1\"")],
+            SystemTime::now(),
+        );
+        cache
+            .insert("http://example.com:80/page".to_string(), stored)
+            .await;
+
+        let mut req = request("/page", &[]);
+        let Lookup::Forward(cache_request) = cache.lookup(&mut req, &addr, false).await else {
+            panic!("answered without revalidating");
+        };
+        assert!(cache_request.revalidating.is_some());
+        assert_eq!(req.headers()[IF_NONE_MATCH], "\"v1\"");
+
+        let mut not_modified = Response::new(HttpBody::empty());
+        *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+        *not_modified.headers_mut() = headers(&[
+            ("cache-control", "max-age=60"),
+            ("etag", "\"v1\""),
+            ("connection", "close"),
+        ]);
+        let res = cache.store(cache_request, not_modified).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CACHE_CONTROL], "max-age=60");
+        assert!(!res.headers().contains_key(CONNECTION));
+        assert_eq!(body(res).await, "stored");
+
+        let mut req = request("/page", &[]);
+        match cache.lookup(&mut req, &addr, false).await {
+            Lookup::Answer(res) => assert_eq!(res.headers()[CACHE_CONTROL], "max-age=60"),
+            Lookup::Forward(_) => panic!("freshened entry was not used"),
+        }
+    }
+}
@@ -1,11 +1,304 @@
-#[derive(Default)]
-pub enum AuthMethod {
-    #[default]
-    NoAuth,
-}
+use std::{sync::Arc, time::Duration};
+
+use ipnet::IpNet;
 
-#[derive(Default)]
-#[allow(dead_code)]
+use super::{HttpCache, HttpInterceptor};
+use crate::{
+    error::ConfigError,
+    limit::Shaper,
+    mitm::Interception,
+    tls::{TlsAcceptor, TlsConnector},
+    Acl, Authenticator, BandwidthLimit, BufferSizes, Credentials, QuotaStore, RecordSink, Timeouts,
+};
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "ConfigBuilder")
+)]
 pub struct Config {
-    pub auth_method: AuthMethod,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) buffers: BufferSizes,
+    pub(crate) acl: Acl,
+    pub(crate) credentials: Option<Credentials>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) authenticator: Option<Arc<dyn Authenticator>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) record_sink: Option<Arc<dyn RecordSink>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) quota_store: Option<Arc<dyn QuotaStore>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) tls_acceptor: Option<TlsAcceptor>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) tls_connector: Option<TlsConnector>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) interception: Option<Interception>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) interceptors: Vec<Arc<dyn HttpInterceptor>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) cache: Option<HttpCache>,
+    pub(crate) bandwidth: BandwidthLimit,
+    pub(crate) global_bandwidth: BandwidthLimit,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) shaper: Shaper,
+}
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    pub fn buffers(&self) -> &BufferSizes {
+        &self.buffers
+    }
+
+    pub fn acl(&self) -> &Acl {
+        &self.acl
+    }
+
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    pub fn authenticator(&self) -> Option<&Arc<dyn Authenticator>> {
+        self.authenticator.as_ref()
+    }
+
+    pub fn record_sink(&self) -> Option<&Arc<dyn RecordSink>> {
+        self.record_sink.as_ref()
+    }
+
+    pub fn quota_store(&self) -> Option<&Arc<dyn QuotaStore>> {
+        self.quota_store.as_ref()
+    }
+
+    #[cfg(feature = "rustls")]
+    pub fn tls_acceptor(&self) -> Option<&TlsAcceptor> {
+        self.tls_acceptor.as_ref()
+    }
+
+    #[cfg(feature = "rustls")]
+    pub fn tls_connector(&self) -> Option<&TlsConnector> {
+        self.tls_connector.as_ref()
+    }
+
+    #[cfg(feature = "mitm")]
+    pub fn interception(&self) -> Option<&Interception> {
+        self.interception.as_ref()
+    }
+
+    pub fn interceptors(&self) -> &[Arc<dyn HttpInterceptor>] {
+        &self.interceptors
+    }
+
+    pub fn cache(&self) -> Option<&HttpCache> {
+        self.cache.as_ref()
+    }
+
+    pub fn bandwidth(&self) -> &BandwidthLimit {
+        &self.bandwidth
+    }
+
+    pub fn global_bandwidth(&self) -> &BandwidthLimit {
+        &self.global_bandwidth
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct ConfigBuilder {
+    timeouts: Timeouts,
+    buffers: BufferSizes,
+    acl: Acl,
+    credentials: Option<Credentials>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    authenticator: Option<Arc<dyn Authenticator>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    record_sink: Option<Arc<dyn RecordSink>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    quota_store: Option<Arc<dyn QuotaStore>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    tls_acceptor: Option<TlsAcceptor>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    tls_connector: Option<TlsConnector>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    interception: Option<Interception>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    interceptors: Vec<Arc<dyn HttpInterceptor>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    cache: Option<HttpCache>,
+    bandwidth: BandwidthLimit,
+    global_bandwidth: BandwidthLimit,
+}
+
+impl ConfigBuilder {
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.handshake = Some(timeout);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.connect = Some(timeout);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.idle = Some(timeout);
+        self
+    }
+
+    pub fn max_lifetime(mut self, lifetime: Duration) -> Self {
+        self.timeouts.lifetime = Some(lifetime);
+        self
+    }
+
+    pub fn buffers(mut self, buffers: BufferSizes) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffers.upload = size;
+        self.buffers.download = size;
+        self
+    }
+
+    pub fn upload_buffer_size(mut self, size: usize) -> Self {
+        self.buffers.upload = size;
+        self
+    }
+
+    pub fn download_buffer_size(mut self, size: usize) -> Self {
+        self.buffers.download = size;
+        self
+    }
+
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    pub fn allow(mut self, net: IpNet) -> Self {
+        self.acl.allow.push(net);
+        self
+    }
+
+    pub fn deny(mut self, net: IpNet) -> Self {
+        self.acl.deny.push(net);
+        self
+    }
+
+    pub fn authenticator(mut self, authenticator: impl Authenticator) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    pub fn record_sink(mut self, sink: impl RecordSink) -> Self {
+        self.record_sink = Some(Arc::new(sink));
+        self
+    }
+
+    pub fn quota_store(mut self, store: impl QuotaStore) -> Self {
+        self.quota_store = Some(Arc::new(store));
+        self
+    }
+
+    // Clients must speak TLS to this server before the proxy protocol.
+    #[cfg(feature = "rustls")]
+    pub fn tls_acceptor(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls_acceptor = Some(acceptor);
+        self
+    }
+
+    // Wraps the connection to the proxy in TLS before the proxy protocol.
+    #[cfg(feature = "rustls")]
+    pub fn tls_connector(mut self, connector: TlsConnector) -> Self {
+        self.tls_connector = Some(connector);
+        self
+    }
+
+    // CONNECT tunnels it applies to are decrypted and their requests
+    // surfaced as items instead of being relayed as opaque streams.
+    #[cfg(feature = "mitm")]
+    pub fn interception(mut self, interception: Interception) -> Self {
+        self.interception = Some(interception);
+        self
+    }
+
+    // Appended to the chain run on every forwarded request and response.
+    pub fn interceptor(mut self, interceptor: impl HttpInterceptor) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    // Plain HTTP requests are answered from `cache` when it has a fresh
+    // response, and cacheable responses are stored in it.
+    pub fn cache(mut self, cache: HttpCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn bandwidth(mut self, limit: BandwidthLimit) -> Self {
+        self.bandwidth = limit;
+        self
+    }
+
+    pub fn global_bandwidth(mut self, limit: BandwidthLimit) -> Self {
+        self.global_bandwidth = limit;
+        self
+    }
+
+    pub fn build(self) -> Result<Config, ConfigError> {
+        if let Some(credentials) = &self.credentials {
+            credentials.validate()?;
+        }
+        self.timeouts.validate()?;
+        self.buffers.validate()?;
+        self.bandwidth.validate()?;
+        self.global_bandwidth.validate()?;
+        Ok(Config {
+            timeouts: self.timeouts,
+            buffers: self.buffers,
+            acl: self.acl,
+            credentials: self.credentials,
+            authenticator: self.authenticator,
+            record_sink: self.record_sink,
+            quota_store: self.quota_store,
+            tls_acceptor: self.tls_acceptor,
+            tls_connector: self.tls_connector,
+            interception: self.interception,
+            interceptors: self.interceptors,
+            cache: self.cache,
+            shaper: Shaper::new(self.bandwidth, &self.global_bandwidth),
+            bandwidth: self.bandwidth,
+            global_bandwidth: self.global_bandwidth,
+        })
+    }
+}
+
+impl TryFrom<ConfigBuilder> for Config {
+    type Error = ConfigError;
+
+    fn try_from(builder: ConfigBuilder) -> Result<Self, Self::Error> {
+        builder.build()
+    }
 }
@@ -0,0 +1,38 @@
+use std::{fmt, future::Future, pin::Pin};
+
+use hyper::{Request, Response};
+
+use super::HttpBody;
+use crate::{DestinationAddress, PeerInfo};
+
+// Inspects and rewrites plain HTTP exchanges as they are served. Requests
+// pass through the interceptors in the order they were added and responses
+// in reverse. Whoever replaces a body also owns its `Content-Length`.
+pub trait HttpInterceptor: Send + Sync + 'static {
+    // Sees the request in origin form, before the cache is consulted and the
+    // request is sent upstream.
+    fn request<'a>(
+        &'a self,
+        _req: &'a mut Request<HttpBody>,
+        _addr: &'a DestinationAddress,
+        _peer: &'a PeerInfo,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async {})
+    }
+
+    // Sees the origin's response before it is sent back to the client.
+    fn response<'a>(
+        &'a self,
+        _res: &'a mut Response<HttpBody>,
+        _addr: &'a DestinationAddress,
+        _peer: &'a PeerInfo,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async {})
+    }
+}
+
+impl fmt::Debug for dyn HttpInterceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HttpInterceptor")
+    }
+}
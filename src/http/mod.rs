@@ -1,58 +1,144 @@
+mod body;
+mod cache;
 pub mod config;
-use std::{pin::Pin, str::FromStr, time::Duration};
+mod interceptor;
+use std::{
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
-pub use config::Config as HttpConfig;
+pub use body::HttpBody;
+use body::{BoxError, Metered};
+pub use cache::HttpCache;
+use cache::{CacheRequest, Lookup};
+pub use config::{Config as HttpConfig, ConfigBuilder as HttpConfigBuilder};
+pub use interceptor::HttpInterceptor;
 
 use hyper::{
     body::{Body, Bytes, Incoming},
-    header::HOST,
+    header::{
+        HeaderName, CONNECTION, CONTENT_LENGTH, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION,
+        TRANSFER_ENCODING, UPGRADE,
+    },
     service::Service,
     upgrade::Upgraded,
     Request, Response,
 };
-use hyper_util::rt::TokioIo;
-use log::{debug, warn};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use log::{debug, info, warn};
 use resumable_io::ResumableIO;
 use tokio::sync::mpsc::UnboundedReceiver;
 
 use crate::{
     address::ToSocketDestination,
-    error::{http::HttpError, ProxyStreamError},
-    AsyncSocket, DestinationAddress, ReplayStatus,
+    error::{http::HttpError, ProxyStreamError, TimeoutKind},
+    limit::Throttle,
+    metrics,
+    quota::{enforce, QuotaLease},
+    record::{CloseReason, Counted, Counters, PendingRecord, ProxyProtocol, Side},
+    relay::Tunnel,
+    timeout::{timeout, IdleTimeout},
+    tls::MaybeTls,
+    trace::Span,
+    Action, AsyncSocket, Credentials, DestinationAddress, PeerInfo, ReplayStatus,
 };
 
 pub struct Http;
 
-#[allow(dead_code)]
 pub struct HttpServer {
-    config: HttpConfig,
     receiver: UnboundedReceiver<ServerInterrupted>,
 }
 
 impl Http {
-    pub fn new_server(config: HttpConfig, socket_stream: impl AsyncSocket) -> HttpServer {
+    pub fn new_server(
+        config: impl Into<Arc<HttpConfig>>,
+        socket_stream: impl AsyncSocket,
+    ) -> HttpServer {
+        Self::new_server_with_peer(config, socket_stream, PeerInfo::default())
+    }
+    pub fn new_server_with_peer(
+        config: impl Into<Arc<HttpConfig>>,
+        socket_stream: impl AsyncSocket,
+        peer: impl Into<PeerInfo>,
+    ) -> HttpServer {
+        let (server, connection) =
+            Self::server(config, socket_stream, peer, std::future::pending());
+        tokio::task::spawn(connection);
+        server
+    }
+    // The connection is served by the returned future. Once `shutdown`
+    // completes it stops reading requests, answers the one in flight and ends.
+    pub(crate) fn server(
+        config: impl Into<Arc<HttpConfig>>,
+        socket_stream: impl AsyncSocket,
+        peer: impl Into<PeerInfo>,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> (HttpServer, impl Future<Output = ()> + Send + 'static) {
+        let config = config.into();
+        let peer = peer.into();
+        let allowed = config.acl.is_allowed(&peer);
+        let span = Span::connection("http", &peer);
+        if !allowed {
+            info!("Denied HTTP client {:?} by ACL", peer.addr);
+            span.event("denied by ACL");
+        }
         let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
-        let http = hyper::server::conn::http1::Builder::new();
-        tokio::task::spawn(async move {
-            if let Err(e) = http
-                .serve_connection(
-                    hyper_util::rt::tokio::TokioIo::new(socket_stream),
-                    ServerService { sender },
-                )
-                .with_upgrades()
-                .await
-            {
+        let mut http = hyper::server::conn::http1::Builder::new();
+        http.timer(TokioTimer::new())
+            .header_read_timeout(config.timeouts.handshake);
+        let acceptor = config.tls_acceptor.clone();
+        let handshake = config.timeouts.handshake;
+        let service = ServerService {
+            sender,
+            config: config.clone(),
+            peer,
+            allowed,
+            span: span.clone(),
+            intercepted: None,
+        };
+        let connection = span.clone().instrument(async move {
+            // A denied client gets no 403 over TLS, just a closed socket.
+            if !allowed && acceptor.is_some() {
+                return;
+            }
+            tokio::pin!(shutdown);
+            let tls = MaybeTls::accept(socket_stream, acceptor.as_ref());
+            let socket_stream = tokio::select! {
+                tls = timeout(handshake, TimeoutKind::Handshake, tls) => match tls {
+                    Ok(socket_stream) => socket_stream,
+                    Err(e) => {
+                        span.failed("tls handshake failed", &e);
+                        return;
+                    }
+                },
+                _ = &mut shutdown => return,
+            };
+            let connection = http
+                .serve_connection(hyper_util::rt::tokio::TokioIo::new(socket_stream), service)
+                .with_upgrades();
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = &mut shutdown => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
                 debug!("{:?}", e);
             };
         });
-        HttpServer { config, receiver }
+        (HttpServer { receiver }, connection)
     }
     pub fn new_client(
-        config: HttpConfig,
+        config: impl Into<Arc<HttpConfig>>,
         socket_stream: impl AsyncSocket,
     ) -> HttpClient<impl AsyncSocket> {
         HttpClient {
-            config,
+            config: config.into(),
             stream: Some(socket_stream),
         }
     }
@@ -70,22 +156,57 @@ impl ServerInterrupted {
             ServerInterrupted::Request(item) => &item.addr,
         }
     }
+    pub fn peer(&self) -> &PeerInfo {
+        match self {
+            ServerInterrupted::Connect(stream) => stream.peer(),
+            ServerInterrupted::Request(item) => item.peer(),
+        }
+    }
     pub async fn serve(self, socket_stream: impl AsyncSocket) -> Result<(), ProxyStreamError> {
         match self {
             ServerInterrupted::Connect(stream) => stream.serve(socket_stream).await,
             ServerInterrupted::Request(item) => item.serve(socket_stream).await,
         }
     }
+    pub async fn serve_direct(self) -> Result<(), ProxyStreamError> {
+        match self {
+            ServerInterrupted::Connect(stream) => stream.serve_direct().await,
+            ServerInterrupted::Request(item) => item.serve_direct().await,
+        }
+    }
+    pub async fn serve_with<S: AsyncSocket>(
+        self,
+        upstream: impl Future<Output = Result<S, ProxyStreamError>>,
+    ) -> Result<(), ProxyStreamError> {
+        match self {
+            ServerInterrupted::Connect(stream) => stream.serve_with(upstream).await,
+            ServerInterrupted::Request(item) => item.serve_with(upstream).await,
+        }
+    }
+    pub async fn serve_action(self, action: &Action) -> Result<(), ProxyStreamError> {
+        match self {
+            ServerInterrupted::Connect(stream) => stream.serve_action(action).await,
+            ServerInterrupted::Request(item) => item.serve_action(action).await,
+        }
+    }
+    pub async fn replay_error(self, error: crate::ReplayStatus) -> Result<(), ProxyStreamError> {
+        match self {
+            ServerInterrupted::Connect(stream) => stream.replay_error(error).await,
+            ServerInterrupted::Request(item) => item.replay_error(error).await,
+        }
+    }
 }
 
 impl HttpServer {
+    // Waits for as long as the connection lives: a keep-alive client may be
+    // idle between requests, and slow request heads are already bounded by
+    // hyper's header read timeout.
     pub async fn accept(&mut self) -> Result<ServerInterrupted, ProxyStreamError> {
         self.receiver.recv().await.ok_or(ProxyStreamError::Closed)
     }
 }
-#[allow(dead_code)]
 pub struct HttpClient<T> {
-    config: HttpConfig,
+    config: Arc<HttpConfig>,
     stream: Option<T>,
 }
 
@@ -95,19 +216,39 @@ impl<T: AsyncSocket> HttpClient<T> {
         addr: impl ToSocketDestination,
     ) -> Result<impl AsyncSocket, ProxyStreamError> {
         let addr = addr.to_destination_address()?.to_string();
+        let stream = self.stream.take().ok_or(ProxyStreamError::Closed)?;
+        let connector = self.config.tls_connector.as_ref();
+        timeout(
+            self.config.timeouts.handshake,
+            TimeoutKind::Handshake,
+            async {
+                let stream = MaybeTls::connect(stream, connector).await?;
+                Self::handshake(stream, addr, self.config.credentials.as_ref()).await
+            },
+        )
+        .await
+    }
+
+    async fn handshake(
+        stream: MaybeTls<T>,
+        addr: String,
+        credentials: Option<&crate::Credentials>,
+    ) -> Result<impl AsyncSocket, ProxyStreamError> {
         let (mut sender, conn) = hyper::client::conn::http1::Builder::new()
-            .handshake(hyper_util::rt::TokioIo::new(
-                self.stream.take().ok_or(ProxyStreamError::Closed)?,
-            ))
+            .handshake(hyper_util::rt::TokioIo::new(stream))
             .await
-            .or(Err(HttpError::BuildHttpReq))?;
-        let req = hyper::Request::builder()
+            .map_err(HttpError::BuildHttpReq)?;
+        let mut req = hyper::Request::builder()
             .method("CONNECT")
             .uri(addr.clone())
             .header(HOST, addr)
-            .header("Proxy-Connection", "keep-alive")
+            .header("Proxy-Connection", "keep-alive");
+        if let Some(credentials) = credentials {
+            req = req.header(PROXY_AUTHORIZATION, credentials.to_basic());
+        }
+        let req = req
             .body(IncomingWrapper::new(None))
-            .or(Err(HttpError::CreateHttpReq))?;
+            .map_err(HttpError::CreateHttpReq)?;
 
         tokio::task::spawn(async move {
             if let Err(err) = conn.with_upgrades().await {
@@ -118,7 +259,10 @@ impl<T: AsyncSocket> HttpClient<T> {
         let res = sender
             .send_request(req)
             .await
-            .or(Err(HttpError::SendHttpReq))?;
+            .map_err(HttpError::SendHttpReq)?;
+        if !res.status().is_success() {
+            Err(HttpError::UnexpectedStatus(res.status()))?;
+        }
         hyper::upgrade::on(res)
             .await
             .map(hyper_util::rt::tokio::TokioIo::new)
@@ -128,10 +272,17 @@ impl<T: AsyncSocket> HttpClient<T> {
 
 pub struct ServerService {
     sender: tokio::sync::mpsc::UnboundedSender<ServerInterrupted>,
+    config: Arc<HttpConfig>,
+    peer: PeerInfo,
+    allowed: bool,
+    span: Span,
+    // The CONNECT destination when serving the inside of an intercepted
+    // tunnel.
+    intercepted: Option<DestinationAddress>,
 }
 
 impl Service<hyper::Request<Incoming>> for ServerService {
-    type Response = Response<IncomingWrapper>;
+    type Response = Response<HttpBody>;
 
     type Error = hyper::Error;
 
@@ -141,7 +292,84 @@ impl Service<hyper::Request<Incoming>> for ServerService {
 
     fn call(&self, req: hyper::Request<Incoming>) -> Self::Future {
         let sender = self.sender.clone();
-        Box::pin(async move {
+        let config = self.config.clone();
+        let mut peer = self.peer.clone();
+        let allowed = self.allowed;
+        let intercepted = self.intercepted.clone();
+        let start = SystemTime::now();
+        let started = Instant::now();
+        let proxy = if req.method() == hyper::Method::CONNECT {
+            ProxyProtocol::HttpConnect
+        } else {
+            ProxyProtocol::Http
+        };
+        let connection = self.span.clone();
+        let span = connection.request(req.method().as_str());
+        Box::pin(span.clone().instrument(async move {
+            if !allowed {
+                metrics::reply(proxy, ReplayStatus::ConnectionNotAllowedByRuleset);
+                let mut response = hyper::Response::new(HttpBody::empty());
+                *response.status_mut() =
+                    ReplayStatus::ConnectionNotAllowedByRuleset.to_status_code();
+                return Ok(response);
+            }
+            // Requests inside an intercepted tunnel were authenticated by
+            // its CONNECT.
+            if let Some(authenticator) = config
+                .authenticator
+                .as_ref()
+                .filter(|_| intercepted.is_none())
+            {
+                let credentials = req
+                    .headers()
+                    .get(PROXY_AUTHORIZATION)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(Credentials::from_basic);
+                let authenticated = match &credentials {
+                    Some(credentials) => {
+                        span.auth()
+                            .instrument(authenticator.authenticate(credentials, &peer))
+                            .await
+                    }
+                    None => false,
+                };
+                match credentials {
+                    Some(credentials) if authenticated => {
+                        connection.record_user(&credentials.username);
+                        span.event("authenticated");
+                        peer.user = Some(credentials.username);
+                    }
+                    _ => {
+                        if credentials.is_some() {
+                            info!("Rejected HTTP client {:?} credentials", peer.addr);
+                            metrics::auth_failure(proxy);
+                            span.event("authentication failed");
+                        } else {
+                            span.event("authentication required");
+                        }
+                        metrics::handshake(proxy, false, started.elapsed());
+                        let mut response = hyper::Response::new(HttpBody::empty());
+                        *response.status_mut() = hyper::StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+                        response.headers_mut().insert(
+                            PROXY_AUTHENTICATE,
+                            hyper::header::HeaderValue::from_static("Basic realm=\"proxy\""),
+                        );
+                        return Ok(response);
+                    }
+                }
+            }
+            let quota = match QuotaLease::acquire(config.quota_store.as_ref(), peer.user.as_deref())
+                .await
+            {
+                Ok(quota) => quota,
+                Err(_) => {
+                    metrics::handshake(proxy, false, started.elapsed());
+                    span.event("quota exceeded");
+                    let mut response = hyper::Response::new(HttpBody::empty());
+                    *response.status_mut() = hyper::StatusCode::TOO_MANY_REQUESTS;
+                    return Ok(response);
+                }
+            };
             if req.method() == hyper::Method::CONNECT {
                 let host = req.headers().get("host").and_then(|s| {
                     s.to_str().ok().map(|s| {
@@ -157,12 +385,40 @@ impl Service<hyper::Request<Incoming>> for ServerService {
                     .authority()
                     .map(|a| a.as_str())
                     .filter(|a| if let Some(h) = host { a == &h } else { true })
+                    .filter(|_| intercepted.is_none())
                     .and_then(|a| DestinationAddress::from_str(a).ok())
                 else {
-                    let mut response = hyper::Response::new(IncomingWrapper::new(None));
+                    metrics::handshake(proxy, false, started.elapsed());
+                    span.event("bad request");
+                    let mut response = hyper::Response::new(HttpBody::empty());
                     *response.status_mut() = hyper::StatusCode::BAD_REQUEST;
                     return Ok::<_, hyper::Error>(response);
                 };
+                metrics::handshake(proxy, true, started.elapsed());
+                connection.record_destination(&addr);
+                span.record_destination(&addr);
+                span.event("request received");
+
+                if config
+                    .interception
+                    .as_ref()
+                    .is_some_and(|i| i.applies(&addr, &peer))
+                {
+                    span.event("intercepted");
+                    metrics::reply(proxy, ReplayStatus::Succeeded);
+                    // Each decrypted request takes a lease of its own.
+                    drop(quota);
+                    let service = ServerService {
+                        sender,
+                        config: config.clone(),
+                        peer,
+                        allowed,
+                        span: connection.clone(),
+                        intercepted: Some(addr),
+                    };
+                    tokio::spawn(connection.instrument(intercept(req, service)));
+                    return Ok(hyper::Response::new(HttpBody::empty()));
+                }
 
                 let (stream, mut stream_controller) =
                     ResumableIO::<TokioIo<Upgraded>>::new(None, Duration::from_secs(10));
@@ -173,23 +429,27 @@ impl Service<hyper::Request<Incoming>> for ServerService {
                         addr: addr.clone(),
                         status_sender,
                         stream,
+                        config: config.clone(),
+                        peer: peer.clone(),
+                        start,
+                        span: span.clone(),
+                        quota,
                     }))
                     .is_err()
                 {
-                    let mut response = hyper::Response::new(IncomingWrapper::new(None));
+                    let mut response = hyper::Response::new(HttpBody::empty());
                     *response.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
                     return Ok::<_, hyper::Error>(response);
                 }
                 match status_receiver.await {
                     Ok(status) => match status {
                         ReplayStatus::Succeeded => {
-                            let mut response = hyper::Response::new(IncomingWrapper::new(None));
+                            let mut response = hyper::Response::new(HttpBody::empty());
 
                             let intrupted = match stream_controller.recv().await {
                                 Some(i) => i,
                                 None => {
-                                    let mut response =
-                                        hyper::Response::new(IncomingWrapper::new(None));
+                                    let mut response = hyper::Response::new(HttpBody::empty());
                                     *response.status_mut() =
                                         hyper::StatusCode::INTERNAL_SERVER_ERROR;
                                     return Ok::<_, hyper::Error>(response);
@@ -216,60 +476,70 @@ impl Service<hyper::Request<Incoming>> for ServerService {
                             Ok(response)
                         }
                         _ => {
-                            let mut response = hyper::Response::new(IncomingWrapper::new(None));
+                            let mut response = hyper::Response::new(HttpBody::empty());
                             *response.status_mut() = status.to_status_code();
                             Ok(response)
                         }
                     },
                     Err(e) => {
                         warn!("{:?}", e);
-                        let mut response = hyper::Response::new(IncomingWrapper::new(None));
+                        let mut response = hyper::Response::new(HttpBody::empty());
                         *response.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
                         Ok(response)
                     }
                 }
             } else {
-                let host = match req
-                    .headers()
-                    .get("host")
-                    .and_then(|s| {
-                        s.to_str().ok().map(|s| {
-                            if s.contains(':') {
-                                s.to_string()
-                            } else {
-                                format!("{}:80", s)
-                            }
+                let is_intercepted = intercepted.is_some();
+                let host = match intercepted.or_else(|| {
+                    req.headers()
+                        .get("host")
+                        .and_then(|s| {
+                            s.to_str().ok().map(|s| {
+                                if s.contains(':') {
+                                    s.to_string()
+                                } else {
+                                    format!("{}:80", s)
+                                }
+                            })
                         })
-                    })
-                    .and_then(|s| DestinationAddress::from_str(&s).ok())
-                {
+                        .and_then(|s| DestinationAddress::from_str(&s).ok())
+                }) {
                     Some(host) => host,
                     None => {
-                        let mut response = hyper::Response::new(IncomingWrapper::new(None));
+                        metrics::handshake(proxy, false, started.elapsed());
+                        span.event("bad request");
+                        let mut response = hyper::Response::new(HttpBody::empty());
                         *response.status_mut() = hyper::StatusCode::BAD_REQUEST;
                         return Ok(response);
                     }
                 };
+                metrics::handshake(proxy, true, started.elapsed());
+                connection.record_destination(&host);
+                span.record_destination(&host);
+                span.event("request received");
                 let (res_sender, res_receiver) = tokio::sync::oneshot::channel();
                 if let Err(e) = sender.send(ServerInterrupted::Request(ServerInterruptedHttpItem {
                     addr: host,
                     req,
+                    body: None,
                     res: res_sender,
+                    config: config.clone(),
+                    peer,
+                    start,
+                    span: span.clone(),
+                    quota,
+                    intercepted: is_intercepted,
                 })) {
                     warn!("{:?}", e);
-                    let mut response = hyper::Response::new(IncomingWrapper::new(None));
+                    let mut response = hyper::Response::new(HttpBody::empty());
                     *response.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
                     return Ok(response);
                 };
-                let res = res_receiver.await.map(|res| {
-                    let (parts, body) = res.into_parts();
-                    Response::from_parts(parts, IncomingWrapper::new(body))
-                });
-                let res = match res {
+                let res = match res_receiver.await {
                     Ok(res) => res,
                     Err(e) => {
                         warn!("{:?}", e);
-                        let mut res = hyper::Response::new(IncomingWrapper::new(None));
+                        let mut res = hyper::Response::new(HttpBody::empty());
                         *res.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
                         res
                     }
@@ -277,7 +547,7 @@ impl Service<hyper::Request<Incoming>> for ServerService {
 
                 Ok(res)
             }
-        })
+        }))
     }
 }
 
@@ -285,10 +555,16 @@ pub struct ServerInterruptedHttpStream {
     addr: DestinationAddress,
     status_sender: tokio::sync::oneshot::Sender<ReplayStatus>,
     stream: ResumableIO<TokioIo<Upgraded>>,
+    config: Arc<HttpConfig>,
+    peer: PeerInfo,
+    start: SystemTime,
+    span: Span,
+    quota: Option<QuotaLease>,
 }
 
 impl ServerInterruptedHttpStream {
     pub async fn proxied_stream(self) -> Result<impl AsyncSocket, ProxyStreamError> {
+        metrics::reply(ProxyProtocol::HttpConnect, ReplayStatus::Succeeded);
         self.status_sender
             .send(ReplayStatus::Succeeded)
             .map_err(|_| ProxyStreamError::Closed)?;
@@ -296,6 +572,7 @@ impl ServerInterruptedHttpStream {
     }
 
     pub async fn replay_error(self, error: crate::ReplayStatus) -> Result<(), ProxyStreamError> {
+        metrics::reply(ProxyProtocol::HttpConnect, error);
         self.status_sender
             .send(error)
             .map_err(|_| ProxyStreamError::Closed)?;
@@ -305,20 +582,136 @@ impl ServerInterruptedHttpStream {
     pub fn addr(&self) -> &crate::address::DestinationAddress {
         &self.addr
     }
-    pub async fn serve(self, mut socket_stream: impl AsyncSocket) -> Result<(), ProxyStreamError>
+
+    pub fn peer(&self) -> &PeerInfo {
+        &self.peer
+    }
+    pub async fn serve(self, socket_stream: impl AsyncSocket) -> Result<(), ProxyStreamError>
     where
         Self: Sized,
     {
-        let mut s = self.proxied_stream().await?;
-        _ = tokio::io::copy_bidirectional(&mut s, &mut socket_stream).await?;
+        self.serve_upstream(socket_stream, None).await
+    }
+    async fn serve_upstream(
+        mut self,
+        socket_stream: impl AsyncSocket,
+        upstream: Option<DestinationAddress>,
+    ) -> Result<(), ProxyStreamError> {
+        let config = self.config.clone();
+        let span = self.span.clone();
+        span.upstream(upstream.as_ref());
+        let tunnel = Tunnel {
+            timeouts: config.timeouts,
+            buffers: config.buffers,
+            record: Some(self.pending_record(ProxyProtocol::HttpConnect, upstream)),
+            throttle: self.throttle(),
+            quota: self.quota.take(),
+            zero_copy: false,
+        };
+        let s = self.proxied_stream().await?;
+        let relay = tunnel.relay(s, socket_stream);
+        span.relay().instrument(relay).await.into_result()?;
         Ok(())
     }
+    pub async fn serve_direct(self) -> Result<(), ProxyStreamError> {
+        let addr = self.addr.clone();
+        let connect_timeout = self.config.timeouts.connect;
+        self.serve_connected(async {
+            let stream = crate::timeout::connect(&addr, connect_timeout)
+                .await
+                .map_err(|e| ProxyStreamError::upstream(&addr, e))?;
+            let upstream = stream.peer_addr().ok().map(DestinationAddress::Ip);
+            Ok((stream, upstream))
+        })
+        .await
+    }
+    pub async fn serve_action(self, action: &Action) -> Result<(), ProxyStreamError> {
+        let addr = self.addr.clone();
+        let connect_timeout = self.config.timeouts.connect;
+        let first_hop = action.first_hop().cloned();
+        match action {
+            Action::Direct => self.serve_direct().await,
+            _ => {
+                self.serve_connected(async {
+                    Ok((action.connect(&addr, connect_timeout).await?, first_hop))
+                })
+                .await
+            }
+        }
+    }
+    pub async fn serve_with<S: AsyncSocket>(
+        self,
+        upstream: impl Future<Output = Result<S, ProxyStreamError>>,
+    ) -> Result<(), ProxyStreamError> {
+        self.serve_connected(async { Ok((upstream.await?, None)) })
+            .await
+    }
+    async fn serve_connected<S: AsyncSocket>(
+        self,
+        upstream: impl Future<Output = Result<(S, Option<DestinationAddress>), ProxyStreamError>>,
+    ) -> Result<(), ProxyStreamError> {
+        let started = Instant::now();
+        let upstream = self.span.dial().instrument(upstream).await;
+        metrics::dial(upstream.is_ok(), started.elapsed());
+        match upstream {
+            Ok((socket, upstream)) => self.serve_upstream(socket, upstream).await,
+            Err(e) => {
+                self.span.failed("upstream failed", &e);
+                self.replay_error((&e).into()).await?;
+                Err(e)
+            }
+        }
+    }
+    fn throttle(&self) -> Throttle {
+        self.config.shaper.throttle(
+            self.peer.user.as_deref(),
+            self.config.authenticator.as_ref(),
+        )
+    }
+    fn pending_record(
+        &self,
+        protocol: ProxyProtocol,
+        upstream: Option<DestinationAddress>,
+    ) -> PendingRecord {
+        PendingRecord {
+            sink: self.config.record_sink.clone(),
+            protocol,
+            peer: self.peer.clone(),
+            destination: self.addr.clone(),
+            upstream,
+            start: self.start,
+            span: self.span.clone(),
+        }
+    }
 }
 
 pub struct ServerInterruptedHttpItem {
     addr: DestinationAddress,
     req: Request<Incoming>,
-    res: tokio::sync::oneshot::Sender<Response<Incoming>>,
+    // Replaces the client's request body when set.
+    body: Option<HttpBody>,
+    res: tokio::sync::oneshot::Sender<Response<HttpBody>>,
+    config: Arc<HttpConfig>,
+    peer: PeerInfo,
+    start: SystemTime,
+    span: Span,
+    quota: Option<QuotaLease>,
+    intercepted: bool,
+}
+
+// An item being served: its request is in origin form and has been through
+// the request interceptors.
+struct Exchange {
+    addr: DestinationAddress,
+    req: Request<HttpBody>,
+    cache_request: Option<CacheRequest>,
+    res: tokio::sync::oneshot::Sender<Response<HttpBody>>,
+    config: Arc<HttpConfig>,
+    peer: PeerInfo,
+    start: SystemTime,
+    span: Span,
+    quota: Option<QuotaLease>,
+    intercepted: bool,
 }
 
 impl ServerInterruptedHttpItem {
@@ -326,29 +719,110 @@ impl ServerInterruptedHttpItem {
         Ok(&self.req)
     }
 
-    pub async fn serve(self, socket_stream: impl AsyncSocket) -> Result<(), ProxyStreamError> {
-        let req = self.req;
+    // Method, URI and headers can be rewritten before the request is served.
+    pub fn proxied_item_mut(&mut self) -> &mut Request<Incoming> {
+        &mut self.req
+    }
 
-        let (mut sender, conn) =
-            hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(socket_stream))
+    // Forwards `body` instead of the client's, framed by its own length.
+    pub fn replace_body(&mut self, body: impl Into<HttpBody>) {
+        let headers = self.req.headers_mut();
+        headers.remove(CONTENT_LENGTH);
+        headers.remove(TRANSFER_ENCODING);
+        self.body = Some(body.into());
+    }
+
+    // Whether the request was decrypted from an intercepted CONNECT tunnel,
+    // in which case it is re-encrypted toward the origin when served.
+    pub fn is_intercepted(&self) -> bool {
+        self.intercepted
+    }
+
+    pub async fn serve(self, socket_stream: impl AsyncSocket) -> Result<(), ProxyStreamError> {
+        let Some(exchange) = self.exchange().await? else {
+            return Ok(());
+        };
+        exchange.serve_upstream(socket_stream, None).await
+    }
+    // The interceptors run before the cache is consulted so that what it
+    // stores, and what it varies on, matches the request actually sent.
+    async fn exchange(self) -> Result<Option<Exchange>, ProxyStreamError> {
+        let body = self.body;
+        let req = origin_request(self.req).map(|incoming| body.unwrap_or_else(|| incoming.into()));
+        let mut exchange = Exchange {
+            addr: self.addr,
+            req,
+            cache_request: None,
+            res: self.res,
+            config: self.config,
+            peer: self.peer,
+            start: self.start,
+            span: self.span,
+            quota: self.quota,
+            intercepted: self.intercepted,
+        };
+        for interceptor in &exchange.config.interceptors {
+            interceptor
+                .request(&mut exchange.req, &exchange.addr, &exchange.peer)
+                .await;
+        }
+        exchange.consult_cache().await
+    }
+    pub async fn serve_direct(self) -> Result<(), ProxyStreamError> {
+        let addr = self.addr.clone();
+        let connect_timeout = self.config.timeouts.connect;
+        self.serve_connected(async {
+            let stream = crate::timeout::connect(&addr, connect_timeout)
+                .await
+                .map_err(|e| ProxyStreamError::upstream(&addr, e))?;
+            let upstream = stream.peer_addr().ok().map(DestinationAddress::Ip);
+            Ok((stream, upstream))
+        })
+        .await
+    }
+    pub async fn serve_action(self, action: &Action) -> Result<(), ProxyStreamError> {
+        let addr = self.addr.clone();
+        let connect_timeout = self.config.timeouts.connect;
+        let first_hop = action.first_hop().cloned();
+        match action {
+            Action::Direct => self.serve_direct().await,
+            _ => {
+                self.serve_connected(async {
+                    Ok((action.connect(&addr, connect_timeout).await?, first_hop))
+                })
                 .await
-                .or(Err(HttpError::BuildHttpReq))?;
-        tokio::task::spawn(async move {
-            if let Err(err) = conn.await {
-                debug!("{:?}", err);
             }
-        });
-        let res = sender
-            .send_request(req)
+        }
+    }
+    pub async fn serve_with<S: AsyncSocket>(
+        self,
+        upstream: impl Future<Output = Result<S, ProxyStreamError>>,
+    ) -> Result<(), ProxyStreamError> {
+        self.serve_connected(async { Ok((upstream.await?, None)) })
             .await
-            .or(Err(HttpError::SendHttpReq))?;
-
-        self.res.send(res).or(Err(HttpError::SendHttpRes))?;
-
-        Ok(())
+    }
+    async fn serve_connected<S: AsyncSocket>(
+        self,
+        upstream: impl Future<Output = Result<(S, Option<DestinationAddress>), ProxyStreamError>>,
+    ) -> Result<(), ProxyStreamError> {
+        let Some(exchange) = self.exchange().await? else {
+            return Ok(());
+        };
+        let started = Instant::now();
+        let upstream = exchange.span.dial().instrument(upstream).await;
+        metrics::dial(upstream.is_ok(), started.elapsed());
+        match upstream {
+            Ok((socket, upstream)) => exchange.serve_upstream(socket, upstream).await,
+            Err(e) => {
+                exchange.span.failed("upstream failed", &e);
+                exchange.replay_error((&e).into())?;
+                Err(e)
+            }
+        }
     }
     pub async fn replay_error(self, error: crate::ReplayStatus) -> Result<(), ProxyStreamError> {
-        let mut response = hyper::Response::new(self.req.into_body());
+        metrics::reply(ProxyProtocol::Http, error);
+        let mut response = hyper::Response::new(HttpBody::from(self.req.into_body()));
         *response.status_mut() = error.to_status_code();
         self.res
             .send(response)
@@ -356,9 +830,277 @@ impl ServerInterruptedHttpItem {
         Ok(())
     }
 
+    // Answers the request from this process, e.g. with a local page or a
+    // cached response, without contacting any upstream.
+    pub async fn respond<B>(self, response: Response<B>) -> Result<(), ProxyStreamError>
+    where
+        B: Body<Data = Bytes> + Send + 'static,
+        B::Error: Into<BoxError>,
+    {
+        metrics::reply(ProxyProtocol::Http, ReplayStatus::Succeeded);
+        self.span.event("answered locally");
+        self.res
+            .send(response.map(HttpBody::new))
+            .map_err(|_| ProxyStreamError::Closed)?;
+        Ok(())
+    }
+
     pub fn addr(&self) -> &crate::address::DestinationAddress {
         &self.addr
     }
+
+    pub fn peer(&self) -> &PeerInfo {
+        &self.peer
+    }
+}
+
+impl Exchange {
+    // Answers from the cache when it can; otherwise keeps what is needed to
+    // store or revalidate the response.
+    async fn consult_cache(mut self) -> Result<Option<Self>, ProxyStreamError> {
+        let Some(cache) = self.config.cache.clone() else {
+            return Ok(Some(self));
+        };
+        match cache
+            .lookup(&mut self.req, &self.addr, self.intercepted)
+            .await
+        {
+            Lookup::Answer(mut res) => {
+                self.span.event("answered from cache");
+                for interceptor in self.config.interceptors.iter().rev() {
+                    interceptor.response(&mut res, &self.addr, &self.peer).await;
+                }
+                // Accounted like a forwarded response, with the cache standing
+                // in for the upstream, once the client has the body.
+                let counters = Arc::new(Counters::default());
+                let record = self
+                    .pending_record(ProxyProtocol::Http, None)
+                    .open(counters.clone(), Side::Client);
+                let (done, delivered) = tokio::sync::oneshot::channel();
+                let res = res.map(|body| HttpBody::new(Metered::new(body, counters.clone(), done)));
+                let quota = self.quota.take();
+                tokio::task::spawn(self.span.relay().instrument(async move {
+                    let close_reason = match delivered.await {
+                        Ok(true) => CloseReason::UpstreamEof,
+                        _ => CloseReason::ClientEof,
+                    };
+                    if let Some(quota) = &quota {
+                        quota.report(counters.read() + counters.written()).await;
+                    }
+                    record.finish(close_reason);
+                }));
+                metrics::reply(ProxyProtocol::Http, ReplayStatus::Succeeded);
+                self.res.send(res).or(Err(HttpError::SendHttpRes))?;
+                Ok(None)
+            }
+            Lookup::Forward(request) => {
+                self.cache_request = Some(request);
+                Ok(Some(self))
+            }
+        }
+    }
+    async fn serve_upstream(
+        mut self,
+        socket_stream: impl AsyncSocket,
+        upstream: Option<DestinationAddress>,
+    ) -> Result<(), ProxyStreamError> {
+        match self.forward(socket_stream, upstream).await {
+            Ok(res) => {
+                metrics::reply(ProxyProtocol::Http, ReplayStatus::Succeeded);
+                self.res.send(res).or(Err(HttpError::SendHttpRes))?;
+                Ok(())
+            }
+            Err(e) => {
+                self.span.failed("upstream failed", &e);
+                self.replay_error((&e).into())?;
+                Err(e)
+            }
+        }
+    }
+    // Sends the request over `socket_stream` and returns the response, which
+    // has been stored in the cache and passed through the interceptors.
+    async fn forward(
+        &mut self,
+        socket_stream: impl AsyncSocket,
+        upstream: Option<DestinationAddress>,
+    ) -> Result<Response<HttpBody>, ProxyStreamError> {
+        self.span.upstream(upstream.as_ref());
+        let record = self.pending_record(ProxyProtocol::Http, upstream);
+        let relay = self.span.relay();
+        let timeouts = self.config.timeouts;
+        let throttle = self.throttle();
+        let quota = self.quota.take();
+        let req = std::mem::take(&mut self.req);
+        let counters = Arc::new(Counters::default());
+        let socket_stream = IdleTimeout::new(
+            throttle.upstream(Counted::new(socket_stream, counters.clone())),
+            timeouts.idle,
+        );
+        let interception = self
+            .config
+            .interception
+            .as_ref()
+            .filter(|_| self.intercepted);
+        let socket_stream = match interception {
+            Some(interception) => timeout(
+                timeouts.handshake,
+                TimeoutKind::Handshake,
+                interception.connect(socket_stream, &self.addr),
+            )
+            .await
+            .map_err(|e| e.at_upstream(&self.addr))?,
+            None => MaybeTls::Plain(socket_stream),
+        };
+
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(socket_stream))
+                .await
+                .map_err(HttpError::BuildHttpReq)?;
+        // The upstream connection ends once the response body has been
+        // handed to the client, which is when the exchange is accounted.
+        // The lifetime limit covers streaming that body as well.
+        let record = record.open(counters.clone(), Side::Upstream);
+        let deadline = timeouts
+            .lifetime
+            .map(|lifetime| tokio::time::Instant::now() + lifetime);
+        tokio::task::spawn(relay.instrument(async move {
+            let expired = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let close_reason = tokio::select! {
+                result = conn => match result {
+                    Ok(()) => CloseReason::UpstreamEof,
+                    Err(err) => {
+                        debug!("{:?}", err);
+                        CloseReason::Error(std::io::ErrorKind::Other)
+                    }
+                },
+                _ = enforce(quota.as_ref(), &counters) => CloseReason::QuotaExceeded,
+                _ = expired => CloseReason::Timeout(TimeoutKind::Lifetime),
+            };
+            if let Some(quota) = &quota {
+                quota.report(counters.read() + counters.written()).await;
+            }
+            record.finish(close_reason);
+        }));
+        let interceptors = &self.config.interceptors;
+        let cache = self.config.cache.as_ref();
+        let cache_request = self.cache_request.take();
+        timeout(timeouts.lifetime, TimeoutKind::Lifetime, async {
+            let mut res = sender
+                .send_request(req)
+                .await
+                .map_err(HttpError::SendHttpReq)?
+                .map(HttpBody::from);
+            if let (Some(cache), Some(request)) = (cache, cache_request) {
+                res = cache.store(request, res).await;
+            }
+            for interceptor in interceptors.iter().rev() {
+                interceptor.response(&mut res, &self.addr, &self.peer).await;
+            }
+            Ok::<_, ProxyStreamError>(res)
+        })
+        .await
+    }
+    fn throttle(&self) -> Throttle {
+        self.config.shaper.throttle(
+            self.peer.user.as_deref(),
+            self.config.authenticator.as_ref(),
+        )
+    }
+    fn pending_record(
+        &self,
+        protocol: ProxyProtocol,
+        upstream: Option<DestinationAddress>,
+    ) -> PendingRecord {
+        PendingRecord {
+            sink: self.config.record_sink.clone(),
+            protocol,
+            peer: self.peer.clone(),
+            destination: self.addr.clone(),
+            upstream,
+            start: self.start,
+            span: self.span.clone(),
+        }
+    }
+    fn replay_error(self, error: crate::ReplayStatus) -> Result<(), ProxyStreamError> {
+        metrics::reply(ProxyProtocol::Http, error);
+        let mut response = hyper::Response::new(HttpBody::empty());
+        *response.status_mut() = error.to_status_code();
+        self.res
+            .send(response)
+            .map_err(|_| ProxyStreamError::Closed)?;
+        Ok(())
+    }
+}
+
+// Turns a proxy request into what the origin expects: origin-form target and
+// no hop-by-hop headers.
+fn origin_request<B>(mut req: Request<B>) -> Request<B> {
+    if req.uri().authority().is_some() {
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        if let Ok(uri) = hyper::Uri::from_str(path) {
+            *req.uri_mut() = uri;
+        }
+    }
+    let headers = req.headers_mut();
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_str(name.trim()).ok())
+        .collect::<Vec<_>>();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in [
+        CONNECTION,
+        PROXY_AUTHORIZATION,
+        UPGRADE,
+        HeaderName::from_static("proxy-connection"),
+        HeaderName::from_static("keep-alive"),
+    ] {
+        headers.remove(name);
+    }
+    req
+}
+
+// Serves the requests sent inside an intercepted CONNECT tunnel as items of
+// their own.
+async fn intercept(req: Request<Incoming>, service: ServerService) {
+    let upgraded = match hyper::upgrade::on(req).await {
+        Ok(upgraded) => TokioIo::new(upgraded),
+        Err(e) => {
+            warn!("{:?}", e);
+            return;
+        }
+    };
+    let (Some(interception), Some(addr)) = (&service.config.interception, &service.intercepted)
+    else {
+        return;
+    };
+    let handshake = service.config.timeouts.handshake;
+    let tls = interception.accept(upgraded, addr);
+    let tls = match timeout(handshake, TimeoutKind::Handshake, tls).await {
+        Ok(tls) => tls,
+        Err(e) => {
+            service.span.failed("tls interception failed", &e);
+            return;
+        }
+    };
+    let mut http = hyper::server::conn::http1::Builder::new();
+    http.timer(TokioTimer::new()).header_read_timeout(handshake);
+    if let Err(e) = http.serve_connection(TokioIo::new(tls), service).await {
+        debug!("{:?}", e);
+    }
 }
 
 pub struct IncomingWrapper {
@@ -1,16 +1,70 @@
 pub use address::DestinationAddress;
 use tokio::io::{AsyncRead, AsyncWrite};
 
+mod acl;
 pub(crate) mod address;
-pub(crate) mod error;
+mod auth;
+mod chain;
+mod connector;
+pub mod error;
 mod http;
+mod limit;
+mod metrics;
+mod mitm;
+mod peer;
+mod proxy_url;
+mod quota;
+mod record;
+mod relay;
+mod router;
+mod selector;
+mod server;
+mod socks4;
 mod socks5;
+#[cfg(target_os = "linux")]
+mod splice;
+mod timeout;
+mod tls;
+mod trace;
 
-pub use http::{Http, HttpConfig};
-pub use socks5::{Socks5, SocksConfig};
+pub use acl::Acl;
+pub use auth::{Authenticator, Credentials};
+pub use chain::{Chain, Hop};
+pub use connector::{ProxyConnector, ProxyTunnel};
+pub use error::{ErrorClass, ProxyStreamError, TimeoutKind};
+pub use http::{
+    Http, HttpBody, HttpCache, HttpConfig, HttpConfigBuilder, HttpInterceptor, ServerInterrupted,
+    ServerInterruptedHttpItem, ServerInterruptedHttpStream,
+};
+pub use limit::{BandwidthLimit, Rate, RateLimiter, Throttled};
+#[cfg(feature = "metrics")]
+pub use metrics::render_prometheus;
+#[cfg(feature = "mitm")]
+pub use mitm::Interception;
+pub use peer::PeerInfo;
+pub use proxy_url::{ProxyScheme, ProxySpec};
+pub use quota::{MemoryQuotaStore, Quota, QuotaStore};
+pub use record::{CloseReason, ConnectionRecord, ProxyProtocol, RecordSink, Side};
+pub use relay::{relay, BufferSizes, RelayOutcome};
+pub use router::{Action, Matcher, PortRange, Router, Rule};
+pub use selector::ProxySelector;
+pub use server::{Listener, Server};
+pub use socks5::{
+    AuthMethod as SocksAuthMethod, ServerInterruptedSocks5Stream, Socks5, SocksConfig,
+    SocksConfigBuilder,
+};
+pub use timeout::Timeouts;
+#[cfg(feature = "rustls")]
+pub use tls::{TlsAcceptor, TlsConnector};
 pub trait AsyncSocket: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
 impl<T> AsyncSocket for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum ReplayStatus {
     Succeeded,
     GeneralSocksServerFailure,
@@ -27,3 +81,79 @@ pub enum Protocol {
     Tcp,
     Udp,
 }
+
+impl From<std::io::ErrorKind> for ReplayStatus {
+    fn from(kind: std::io::ErrorKind) -> Self {
+        match kind {
+            std::io::ErrorKind::ConnectionRefused => ReplayStatus::ConnectionRefused,
+            std::io::ErrorKind::HostUnreachable => ReplayStatus::HostUnreachable,
+            std::io::ErrorKind::NetworkUnreachable | std::io::ErrorKind::NetworkDown => {
+                ReplayStatus::NetworkUnreachable
+            }
+            std::io::ErrorKind::TimedOut => ReplayStatus::TtlExpired,
+            std::io::ErrorKind::PermissionDenied => ReplayStatus::ConnectionNotAllowedByRuleset,
+            std::io::ErrorKind::AddrNotAvailable | std::io::ErrorKind::InvalidInput => {
+                ReplayStatus::AddressTypeNotSupported
+            }
+            std::io::ErrorKind::Unsupported => ReplayStatus::CommandNotSupported,
+            _ => ReplayStatus::GeneralSocksServerFailure,
+        }
+    }
+}
+
+impl From<&std::io::Error> for ReplayStatus {
+    fn from(error: &std::io::Error) -> Self {
+        error.kind().into()
+    }
+}
+
+impl From<std::io::Error> for ReplayStatus {
+    fn from(error: std::io::Error) -> Self {
+        (&error).into()
+    }
+}
+
+impl From<&ProxyStreamError> for ReplayStatus {
+    fn from(error: &ProxyStreamError) -> Self {
+        match error {
+            ProxyStreamError::IO(e) | ProxyStreamError::Upstream { source: e, .. } => e.into(),
+            ProxyStreamError::Address(_) => ReplayStatus::AddressTypeNotSupported,
+            ProxyStreamError::Socks(e) => match e {
+                error::SocksError::IOError(e) => e.into(),
+                error::SocksError::CommandNotSupported => ReplayStatus::CommandNotSupported,
+                error::SocksError::CommandFailed(status) => *status,
+                error::SocksError::InvalidAddress | error::SocksError::AddressError(_) => {
+                    ReplayStatus::AddressTypeNotSupported
+                }
+                _ => ReplayStatus::GeneralSocksServerFailure,
+            },
+            ProxyStreamError::Timeout(_) => ReplayStatus::TtlExpired,
+            ProxyStreamError::NotAllowed | ProxyStreamError::QuotaExceeded => {
+                ReplayStatus::ConnectionNotAllowedByRuleset
+            }
+            ProxyStreamError::NotImplemented => ReplayStatus::CommandNotSupported,
+            ProxyStreamError::Http(error::HttpError::UnexpectedStatus(status)) => match *status {
+                hyper::StatusCode::FORBIDDEN | hyper::StatusCode::TOO_MANY_REQUESTS => {
+                    ReplayStatus::ConnectionNotAllowedByRuleset
+                }
+                hyper::StatusCode::GATEWAY_TIMEOUT => ReplayStatus::TtlExpired,
+                hyper::StatusCode::BAD_GATEWAY => ReplayStatus::HostUnreachable,
+                hyper::StatusCode::NOT_IMPLEMENTED => ReplayStatus::CommandNotSupported,
+                _ => ReplayStatus::GeneralSocksServerFailure,
+            },
+            ProxyStreamError::Http(
+                error::HttpError::BuildHttpReq(_) | error::HttpError::SendHttpReq(_),
+            ) => ReplayStatus::HostUnreachable,
+            ProxyStreamError::Config(_)
+            | ProxyStreamError::Http(_)
+            | ProxyStreamError::Relay(_)
+            | ProxyStreamError::Closed => ReplayStatus::GeneralSocksServerFailure,
+        }
+    }
+}
+
+impl From<ProxyStreamError> for ReplayStatus {
+    fn from(error: ProxyStreamError) -> Self {
+        (&error).into()
+    }
+}
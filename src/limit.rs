@@ -0,0 +1,363 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+use crate::{error::ConfigError, Authenticator};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct Rate {
+    pub(crate) bytes_per_sec: u64,
+    // Zero means one second worth of traffic.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) burst: u64,
+}
+
+impl Rate {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Rate {
+            bytes_per_sec,
+            burst: 0,
+        }
+    }
+
+    pub fn with_burst(mut self, burst: u64) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    pub fn burst(&self) -> u64 {
+        if self.burst == 0 {
+            self.bytes_per_sec
+        } else {
+            self.burst
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct BandwidthLimit {
+    pub upload: Option<Rate>,
+    pub download: Option<Rate>,
+}
+
+impl BandwidthLimit {
+    pub fn new(upload: Option<Rate>, download: Option<Rate>) -> Self {
+        BandwidthLimit { upload, download }
+    }
+
+    pub fn symmetric(rate: Rate) -> Self {
+        BandwidthLimit {
+            upload: Some(rate),
+            download: Some(rate),
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if [self.upload, self.download]
+            .iter()
+            .flatten()
+            .any(|rate| rate.bytes_per_sec == 0)
+        {
+            return Err(ConfigError::ZeroRate);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Rate,
+    // May go negative when limiters are shared; the debt is paid back before
+    // anyone is allowed through again.
+    tokens: f64,
+    updated: Instant,
+}
+
+// Token bucket, shared by every stream holding a clone.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Rate) -> Self {
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                tokens: rate.burst() as f64,
+                updated: Instant::now(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Bucket> {
+        self.bucket.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // How many bytes may pass now, or how long to wait until some can.
+    fn available(&self) -> Result<usize, Duration> {
+        let mut bucket = self.lock();
+        let now = Instant::now();
+        let rate = bucket.rate;
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * rate.bytes_per_sec as f64;
+        bucket.tokens = (bucket.tokens + refill).min(rate.burst() as f64);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            Ok(bucket.tokens as usize)
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / rate.bytes_per_sec as f64,
+            ))
+        }
+    }
+
+    fn consume(&self, n: usize) {
+        self.lock().tokens -= n as f64;
+    }
+
+    fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.bucket) > 1
+    }
+}
+
+fn allowance(limiters: &[RateLimiter], wanted: usize) -> Result<usize, Duration> {
+    limiters
+        .iter()
+        .try_fold(wanted, |n, limiter| Ok(n.min(limiter.available()?)))
+}
+
+// Limits reads and writes on `T` to the rates of every attached limiter.
+pub struct Throttled<T> {
+    inner: T,
+    read: Vec<RateLimiter>,
+    write: Vec<RateLimiter>,
+    read_sleep: Option<Pin<Box<Sleep>>>,
+    write_sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T) -> Self {
+        Throttled {
+            inner,
+            read: Vec::new(),
+            write: Vec::new(),
+            read_sleep: None,
+            write_sleep: None,
+        }
+    }
+
+    pub fn read_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.read.push(limiter);
+        self
+    }
+
+    pub fn write_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.write.push(limiter);
+        self
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+fn poll_allowance(
+    limiters: &[RateLimiter],
+    sleep: &mut Option<Pin<Box<Sleep>>>,
+    wanted: usize,
+    cx: &mut Context<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(pending) = sleep.as_mut() {
+            ready!(pending.as_mut().poll(cx));
+            *sleep = None;
+        }
+        match allowance(limiters, wanted) {
+            Ok(n) => return Poll::Ready(n),
+            Err(wait) => *sleep = Some(Box::pin(tokio::time::sleep(wait))),
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Throttled<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.read.is_empty() || buf.remaining() == 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let n = ready!(poll_allowance(
+            &this.read,
+            &mut this.read_sleep,
+            buf.remaining(),
+            cx
+        ));
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(n));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+        for limiter in &this.read {
+            limiter.consume(read);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Throttled<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.write.is_empty() || buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        let n = ready!(poll_allowance(
+            &this.write,
+            &mut this.write_sleep,
+            buf.len(),
+            cx
+        ));
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..n]))?;
+        for limiter in &this.write {
+            limiter.consume(written);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Limiters {
+    upload: Option<RateLimiter>,
+    download: Option<RateLimiter>,
+}
+
+impl From<&BandwidthLimit> for Limiters {
+    fn from(limit: &BandwidthLimit) -> Self {
+        Limiters {
+            upload: limit.upload.map(RateLimiter::new),
+            download: limit.download.map(RateLimiter::new),
+        }
+    }
+}
+
+// The limiters applying to one relay, split by direction.
+#[derive(Default)]
+pub(crate) struct Throttle {
+    upload: Vec<RateLimiter>,
+    download: Vec<RateLimiter>,
+}
+
+impl Throttle {
+    fn push(&mut self, limiters: Limiters) {
+        self.upload.extend(limiters.upload);
+        self.download.extend(limiters.download);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.upload.is_empty() && self.download.is_empty()
+    }
+
+    // Upload is what the client sends, so it is read from the client side.
+    pub(crate) fn client<T>(self, inner: T) -> Throttled<T> {
+        Throttled {
+            read: self.upload,
+            write: self.download,
+            ..Throttled::new(inner)
+        }
+    }
+
+    pub(crate) fn upstream<T>(self, inner: T) -> Throttled<T> {
+        Throttled {
+            read: self.download,
+            write: self.upload,
+            ..Throttled::new(inner)
+        }
+    }
+}
+
+// Per-connection, per-user and server-wide limits of one server config.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Shaper {
+    connection: BandwidthLimit,
+    global: Limiters,
+    users: Arc<Mutex<HashMap<String, (BandwidthLimit, Limiters)>>>,
+}
+
+impl Shaper {
+    pub(crate) fn new(connection: BandwidthLimit, global: &BandwidthLimit) -> Self {
+        Shaper {
+            connection,
+            global: global.into(),
+            users: Default::default(),
+        }
+    }
+
+    pub(crate) fn throttle(
+        &self,
+        user: Option<&str>,
+        authenticator: Option<&Arc<dyn Authenticator>>,
+    ) -> Throttle {
+        let mut throttle = Throttle::default();
+        throttle.push((&self.connection).into());
+        throttle.push(self.global.clone());
+        let limit = user.zip(authenticator).and_then(|(user, authenticator)| {
+            authenticator.bandwidth(user).map(|limit| (user, limit))
+        });
+        if let Some((user, limit)) = limit {
+            let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+            // Forget users with no relay left holding their limiters.
+            users.retain(|_, (_, limiters)| {
+                [&limiters.upload, &limiters.download]
+                    .into_iter()
+                    .flatten()
+                    .any(RateLimiter::is_shared)
+            });
+            let entry = users
+                .entry(user.to_string())
+                .or_insert_with(|| (limit, (&limit).into()));
+            if entry.0 != limit {
+                *entry = (limit, (&limit).into());
+            }
+            throttle.push(entry.1.clone());
+        }
+        throttle
+    }
+}
@@ -0,0 +1,254 @@
+// Server-side instrumentation. With the `metrics` feature every observation is
+// kept in a process-wide registry, renderable with `render_prometheus`, and
+// forwarded to the `metrics` facade; without it these hooks compile to nothing.
+
+#[cfg(feature = "metrics")]
+pub use imp::render_prometheus;
+pub(crate) use imp::*;
+
+#[cfg(feature = "metrics")]
+mod imp {
+    use std::{
+        collections::BTreeMap,
+        fmt::Write,
+        sync::{Mutex, OnceLock},
+        time::Duration,
+    };
+
+    use crate::{ProxyProtocol, ReplayStatus};
+
+    type Labels = Vec<(&'static str, &'static str)>;
+
+    const HANDSHAKES: &str = "proxy_stream_handshakes_total";
+    const REPLIES: &str = "proxy_stream_replies_total";
+    const AUTH_FAILURES: &str = "proxy_stream_auth_failures_total";
+    const ACTIVE_TUNNELS: &str = "proxy_stream_active_tunnels";
+    const BYTES: &str = "proxy_stream_bytes_total";
+    const HANDSHAKE_DURATION: &str = "proxy_stream_handshake_duration_seconds";
+    const DIAL_DURATION: &str = "proxy_stream_upstream_dial_duration_seconds";
+
+    const HELP: &[(&str, &str, &str)] = &[
+        (
+            HANDSHAKES,
+            "counter",
+            "Client handshakes by protocol and outcome.",
+        ),
+        (
+            REPLIES,
+            "counter",
+            "Replies sent to clients by protocol and status.",
+        ),
+        (
+            AUTH_FAILURES,
+            "counter",
+            "Rejected client credentials by protocol.",
+        ),
+        (
+            ACTIVE_TUNNELS,
+            "gauge",
+            "Relays currently in progress by protocol.",
+        ),
+        (BYTES, "counter", "Bytes relayed by protocol and direction."),
+        (HANDSHAKE_DURATION, "histogram", "Client handshake latency."),
+        (
+            DIAL_DURATION,
+            "histogram",
+            "Upstream dial latency by outcome.",
+        ),
+    ];
+
+    const BUCKETS: [f64; 11] = [
+        0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+    ];
+
+    #[derive(Default)]
+    struct Histogram {
+        buckets: [u64; BUCKETS.len()],
+        sum: f64,
+        count: u64,
+    }
+
+    #[derive(Default)]
+    struct Registry {
+        counters: BTreeMap<(&'static str, Labels), u64>,
+        gauges: BTreeMap<(&'static str, Labels), i64>,
+        histograms: BTreeMap<(&'static str, Labels), Histogram>,
+    }
+
+    fn registry() -> std::sync::MutexGuard<'static, Registry> {
+        static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+        REGISTRY
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn protocol(protocol: ProxyProtocol) -> &'static str {
+        match protocol {
+            ProxyProtocol::Socks5 => "socks5",
+            ProxyProtocol::Socks5Udp => "socks5_udp",
+            ProxyProtocol::HttpConnect => "http_connect",
+            ProxyProtocol::Http => "http",
+        }
+    }
+
+    fn outcome(success: bool) -> &'static str {
+        if success {
+            "success"
+        } else {
+            "failure"
+        }
+    }
+
+    fn status(status: ReplayStatus) -> &'static str {
+        match status {
+            ReplayStatus::Succeeded => "succeeded",
+            ReplayStatus::GeneralSocksServerFailure => "general_failure",
+            ReplayStatus::ConnectionNotAllowedByRuleset => "not_allowed",
+            ReplayStatus::NetworkUnreachable => "network_unreachable",
+            ReplayStatus::HostUnreachable => "host_unreachable",
+            ReplayStatus::ConnectionRefused => "connection_refused",
+            ReplayStatus::TtlExpired => "ttl_expired",
+            ReplayStatus::CommandNotSupported => "command_not_supported",
+            ReplayStatus::AddressTypeNotSupported => "address_type_not_supported",
+        }
+    }
+
+    fn counter(name: &'static str, labels: Labels, value: u64) {
+        ::metrics::counter!(name, &labels).increment(value);
+        *registry().counters.entry((name, labels)).or_default() += value;
+    }
+
+    fn gauge(name: &'static str, labels: Labels, delta: i64) {
+        ::metrics::gauge!(name, &labels).increment(delta as f64);
+        *registry().gauges.entry((name, labels)).or_default() += delta;
+    }
+
+    fn histogram(name: &'static str, labels: Labels, value: Duration) {
+        let value = value.as_secs_f64();
+        ::metrics::histogram!(name, &labels).record(value);
+        let mut registry = registry();
+        let histogram = registry.histograms.entry((name, labels)).or_default();
+        for (bucket, le) in histogram.buckets.iter_mut().zip(BUCKETS) {
+            if value <= le {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    pub(crate) fn handshake(proxy: ProxyProtocol, success: bool, elapsed: Duration) {
+        counter(
+            HANDSHAKES,
+            vec![("protocol", protocol(proxy)), ("outcome", outcome(success))],
+            1,
+        );
+        histogram(
+            HANDSHAKE_DURATION,
+            vec![("protocol", protocol(proxy))],
+            elapsed,
+        );
+    }
+
+    pub(crate) fn reply(proxy: ProxyProtocol, replay: ReplayStatus) {
+        counter(
+            REPLIES,
+            vec![("protocol", protocol(proxy)), ("status", status(replay))],
+            1,
+        );
+    }
+
+    pub(crate) fn auth_failure(proxy: ProxyProtocol) {
+        counter(AUTH_FAILURES, vec![("protocol", protocol(proxy))], 1);
+    }
+
+    pub(crate) fn dial(success: bool, elapsed: Duration) {
+        histogram(DIAL_DURATION, vec![("outcome", outcome(success))], elapsed);
+    }
+
+    pub(crate) fn tunnel_opened(proxy: ProxyProtocol) {
+        gauge(ACTIVE_TUNNELS, vec![("protocol", protocol(proxy))], 1);
+    }
+
+    pub(crate) fn tunnel_closed(proxy: ProxyProtocol, bytes_up: u64, bytes_down: u64) {
+        gauge(ACTIVE_TUNNELS, vec![("protocol", protocol(proxy))], -1);
+        counter(
+            BYTES,
+            vec![("protocol", protocol(proxy)), ("direction", "up")],
+            bytes_up,
+        );
+        counter(
+            BYTES,
+            vec![("protocol", protocol(proxy)), ("direction", "down")],
+            bytes_down,
+        );
+    }
+
+    fn labels(labels: &Labels, extra: Option<(&str, &str)>) -> String {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| (*k, *v))
+            .chain(extra)
+            .map(|(k, v)| format!("{}=\"{}\"", k, v))
+            .collect::<Vec<_>>();
+        if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels.join(","))
+        }
+    }
+
+    // Renders every metric observed so far in the Prometheus text exposition
+    // format.
+    pub fn render_prometheus() -> String {
+        let registry = registry();
+        let mut out = String::new();
+        for (name, kind, help) in HELP {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for ((_, l), value) in registry.counters.iter().filter(|((n, _), _)| n == name) {
+                let _ = writeln!(out, "{}{} {}", name, labels(l, None), value);
+            }
+            for ((_, l), value) in registry.gauges.iter().filter(|((n, _), _)| n == name) {
+                let _ = writeln!(out, "{}{} {}", name, labels(l, None), value);
+            }
+            for ((_, l), histogram) in registry.histograms.iter().filter(|((n, _), _)| n == name) {
+                for (bucket, le) in histogram.buckets.iter().zip(BUCKETS) {
+                    let le = le.to_string();
+                    let _ = writeln!(
+                        out,
+                        "{}_bucket{} {}",
+                        name,
+                        labels(l, Some(("le", &le))),
+                        bucket
+                    );
+                }
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    name,
+                    labels(l, Some(("le", "+Inf"))),
+                    histogram.count
+                );
+                let _ = writeln!(out, "{}_sum{} {}", name, labels(l, None), histogram.sum);
+                let _ = writeln!(out, "{}_count{} {}", name, labels(l, None), histogram.count);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod imp {
+    use std::time::Duration;
+
+    use crate::{ProxyProtocol, ReplayStatus};
+
+    pub(crate) fn handshake(_: ProxyProtocol, _: bool, _: Duration) {}
+    pub(crate) fn reply(_: ProxyProtocol, _: ReplayStatus) {}
+    pub(crate) fn auth_failure(_: ProxyProtocol) {}
+    pub(crate) fn dial(_: bool, _: Duration) {}
+    pub(crate) fn tunnel_opened(_: ProxyProtocol) {}
+    pub(crate) fn tunnel_closed(_: ProxyProtocol, _: u64, _: u64) {}
+}
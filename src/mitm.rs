@@ -0,0 +1,257 @@
+// TLS interception of HTTP CONNECT tunnels: the client is served a
+// certificate minted for the destination from a configured CA, and the
+// decrypted requests are re-encrypted toward the origin.
+
+pub use imp::Interception;
+
+#[cfg(feature = "mitm")]
+mod imp {
+    use std::{
+        collections::{HashMap, VecDeque},
+        fmt, io,
+        sync::{Arc, Mutex, MutexGuard},
+        time::{Duration, Instant},
+    };
+
+    use rcgen::{
+        CertificateParams, DnType, ExtendedKeyUsagePurpose, Issuer, KeyPair, KeyUsagePurpose,
+        SerialNumber,
+    };
+    use rustls::{
+        pki_types::{PrivateKeyDer, ServerName},
+        server::Acceptor,
+        ClientConfig, ServerConfig,
+    };
+    use time::OffsetDateTime;
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio_rustls::LazyConfigAcceptor;
+
+    use crate::{
+        error::ConfigError,
+        tls::{native_roots, provider, MaybeTls},
+        DestinationAddress, Matcher, PeerInfo, PortRange,
+    };
+
+    const CERT_VALIDITY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+    const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+    // Only HTTP/1.1 is spoken on either side of the interception.
+    const ALPN: &[u8] = b"http/1.1";
+
+    struct Authority {
+        issuer: Issuer<'static, KeyPair>,
+        // Shared by every minted certificate; only the CA key has to be
+        // trusted, so there is no need for one key per host.
+        key: KeyPair,
+        cache: Mutex<Cache>,
+    }
+
+    #[derive(Default)]
+    struct Cache {
+        configs: HashMap<String, (Instant, Arc<ServerConfig>)>,
+        order: VecDeque<String>,
+    }
+
+    #[derive(Clone)]
+    pub struct Interception {
+        authority: Arc<Authority>,
+        origin: tokio_rustls::TlsConnector,
+        ports: Vec<PortRange>,
+        bypass: Vec<Matcher>,
+        cache_capacity: usize,
+    }
+
+    impl Interception {
+        // Mints certificates from the PEM encoded CA certificate and its
+        // PKCS#8 key. Origins are verified against the system roots unless
+        // `origin` says otherwise.
+        pub fn from_pem(ca_cert: &[u8], ca_key: &[u8]) -> Result<Self, ConfigError> {
+            let ca_cert = std::str::from_utf8(ca_cert).map_err(|_| ConfigError::InvalidPem)?;
+            let ca_key = std::str::from_utf8(ca_key).map_err(|_| ConfigError::InvalidPem)?;
+            let ca_key = KeyPair::from_pem(ca_key).map_err(|_| ConfigError::InvalidPem)?;
+            let issuer =
+                Issuer::from_ca_cert_pem(ca_cert, ca_key).map_err(|_| ConfigError::InvalidPem)?;
+            let mut origin = ClientConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(native_roots())
+                .with_no_client_auth();
+            origin.alpn_protocols = vec![ALPN.to_vec()];
+            Ok(Interception {
+                authority: Arc::new(Authority {
+                    issuer,
+                    key: KeyPair::generate()?,
+                    cache: Mutex::default(),
+                }),
+                origin: Arc::new(origin).into(),
+                ports: vec![443.into()],
+                bypass: Vec::new(),
+                cache_capacity: DEFAULT_CACHE_CAPACITY,
+            })
+        }
+
+        // TLS settings toward the origins, e.g. to trust a private CA.
+        pub fn origin(mut self, config: Arc<ClientConfig>) -> Self {
+            let mut config = Arc::unwrap_or_clone(config);
+            config.alpn_protocols = vec![ALPN.to_vec()];
+            self.origin = Arc::new(config).into();
+            self
+        }
+
+        // Destination ports that are intercepted, 443 by default.
+        pub fn ports(mut self, ports: impl IntoIterator<Item = impl Into<PortRange>>) -> Self {
+            self.ports = ports.into_iter().map(Into::into).collect();
+            self
+        }
+
+        // Tunnels to destinations matching `matcher` are relayed untouched.
+        pub fn bypass(mut self, matcher: Matcher) -> Self {
+            self.bypass.push(matcher);
+            self
+        }
+
+        // How many minted certificates are kept for reuse.
+        pub fn cache_capacity(mut self, capacity: usize) -> Self {
+            self.cache_capacity = capacity;
+            self
+        }
+
+        pub(crate) fn applies(&self, addr: &DestinationAddress, peer: &PeerInfo) -> bool {
+            self.ports.iter().any(|range| range.contains(addr.port()))
+                && !self.bypass.iter().any(|m| m.matches(addr, peer))
+        }
+
+        // Terminates the client's TLS with a certificate for the name it
+        // asked for, or for the CONNECT host when it sent no SNI.
+        pub(crate) async fn accept<T: AsyncRead + AsyncWrite + Unpin>(
+            &self,
+            socket: T,
+            addr: &DestinationAddress,
+        ) -> io::Result<MaybeTls<T>> {
+            let start = LazyConfigAcceptor::new(Acceptor::default(), socket).await?;
+            let name = match start.client_hello().server_name() {
+                Some(name) => name.to_string(),
+                None => addr.host(),
+            };
+            let config = self.server_config(&name)?;
+            let stream = start.into_stream(config).await?;
+            Ok(MaybeTls::Tls(Box::new(stream.into())))
+        }
+
+        pub(crate) async fn connect<T: AsyncRead + AsyncWrite + Unpin>(
+            &self,
+            socket: T,
+            addr: &DestinationAddress,
+        ) -> io::Result<MaybeTls<T>> {
+            let server_name = match addr {
+                DestinationAddress::Domain(..) => ServerName::try_from(addr.host())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+                DestinationAddress::Ip(addr) => ServerName::IpAddress(addr.ip().into()),
+            };
+            let stream = self.origin.connect(server_name, socket).await?;
+            Ok(MaybeTls::Tls(Box::new(stream.into())))
+        }
+
+        fn server_config(&self, name: &str) -> io::Result<Arc<ServerConfig>> {
+            // Renewed well before expiry so long-lived clients never see an
+            // expired certificate.
+            if let Some((minted, config)) = self.cache().configs.get(name) {
+                if minted.elapsed() < CERT_VALIDITY / 2 {
+                    return Ok(config.clone());
+                }
+            }
+            let config = Arc::new(self.mint(name).map_err(io::Error::other)?);
+            let mut cache = self.cache();
+            if cache
+                .configs
+                .insert(name.to_string(), (Instant::now(), config.clone()))
+                .is_none()
+            {
+                cache.order.push_back(name.to_string());
+            }
+            while cache.order.len() > self.cache_capacity {
+                if let Some(oldest) = cache.order.pop_front() {
+                    cache.configs.remove(&oldest);
+                }
+            }
+            Ok(config)
+        }
+
+        fn cache(&self) -> MutexGuard<'_, Cache> {
+            self.authority
+                .cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+        }
+
+        fn mint(&self, name: &str) -> Result<ServerConfig, ConfigError> {
+            let mut serial = [0; 16];
+            provider()
+                .secure_random
+                .fill(&mut serial)
+                .map_err(|_| rcgen::Error::RingUnspecified)?;
+            serial[0] &= 0x7f;
+            let now = OffsetDateTime::now_utc();
+            let mut params = CertificateParams::new(vec![name.to_string()])?;
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.serial_number = Some(SerialNumber::from_slice(&serial));
+            // Backdated to tolerate clients with a slow clock.
+            params.not_before = now - Duration::from_secs(24 * 60 * 60);
+            params.not_after = now + CERT_VALIDITY;
+            params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+            params.use_authority_key_identifier_extension = true;
+            let authority = &self.authority;
+            let cert = params.signed_by(&authority.key, &authority.issuer)?;
+            let key = PrivateKeyDer::Pkcs8(authority.key.serialize_der().into());
+            let mut config = ServerConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()?
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.der().clone()], key)?;
+            config.alpn_protocols = vec![ALPN.to_vec()];
+            Ok(config)
+        }
+    }
+
+    impl fmt::Debug for Interception {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Interception")
+                .field("ports", &self.ports)
+                .field("bypass", &self.bypass)
+                .field("cache_capacity", &self.cache_capacity)
+                .finish()
+        }
+    }
+}
+
+#[cfg(not(feature = "mitm"))]
+mod imp {
+    use std::io;
+
+    use crate::{tls::MaybeTls, DestinationAddress, PeerInfo};
+
+    // Uninhabited: without the feature no tunnel is intercepted.
+    #[derive(Debug, Clone)]
+    pub enum Interception {}
+
+    impl Interception {
+        pub(crate) fn applies(&self, _: &DestinationAddress, _: &PeerInfo) -> bool {
+            match *self {}
+        }
+
+        pub(crate) async fn accept<T>(
+            &self,
+            _: T,
+            _: &DestinationAddress,
+        ) -> io::Result<MaybeTls<T>> {
+            match *self {}
+        }
+
+        pub(crate) async fn connect<T>(
+            &self,
+            _: T,
+            _: &DestinationAddress,
+        ) -> io::Result<MaybeTls<T>> {
+            match *self {}
+        }
+    }
+}
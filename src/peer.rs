@@ -0,0 +1,52 @@
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    pub ucred: Option<tokio::net::unix::UCred>,
+    pub user: Option<String>,
+}
+
+impl PeerInfo {
+    pub fn new(addr: impl Into<Option<SocketAddr>>) -> Self {
+        PeerInfo {
+            addr: addr.into(),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn from_ucred(ucred: tokio::net::unix::UCred) -> Self {
+        PeerInfo {
+            ucred: Some(ucred),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    // IPv4 clients on dual-stack sockets show up as v4-mapped v6 addresses.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.addr.map(|addr| canonical(addr.ip()))
+    }
+}
+
+impl From<SocketAddr> for PeerInfo {
+    fn from(addr: SocketAddr) -> Self {
+        PeerInfo::new(addr)
+    }
+}
+
+pub(crate) fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        v4 => v4,
+    }
+}
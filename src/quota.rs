@@ -0,0 +1,206 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{record::Counters, ProxyStreamError};
+
+// How often relayed bytes are charged to the user's quota.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct Quota {
+    pub max_connections: Option<usize>,
+    pub max_bytes: Option<u64>,
+    // Period after which the byte count starts over; never when unset.
+    #[cfg_attr(feature = "serde", serde(with = "crate::timeout::secs"))]
+    pub window: Option<Duration>,
+}
+
+impl Quota {
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    pub fn max_bytes(mut self, max: u64, window: Duration) -> Self {
+        self.max_bytes = Some(max);
+        self.window = Some(window);
+        self
+    }
+}
+
+pub trait QuotaStore: Send + Sync + 'static {
+    // Opens a tunnel for `user`; false when a limit is already reached.
+    fn acquire<'a>(&'a self, user: &'a str) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>>;
+
+    fn release(&self, user: &str);
+
+    // Charges relayed bytes to `user`; false once the quota is exhausted.
+    fn consume<'a>(
+        &'a self,
+        user: &'a str,
+        bytes: u64,
+    ) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>>;
+}
+
+impl fmt::Debug for dyn QuotaStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("QuotaStore")
+    }
+}
+
+#[derive(Debug)]
+struct Usage {
+    active: usize,
+    bytes: u64,
+    window_start: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryQuotaStore {
+    default: Quota,
+    users: HashMap<String, Quota>,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl MemoryQuotaStore {
+    pub fn new(default: Quota) -> Self {
+        MemoryQuotaStore {
+            default,
+            ..Default::default()
+        }
+    }
+
+    pub fn user(mut self, user: impl Into<String>, quota: Quota) -> Self {
+        self.users.insert(user.into(), quota);
+        self
+    }
+
+    fn quota(&self, user: &str) -> &Quota {
+        self.users.get(user).unwrap_or(&self.default)
+    }
+
+    fn with_usage<R>(&self, user: &str, f: impl FnOnce(&Quota, &mut Usage) -> R) -> R {
+        let quota = self.quota(user);
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let usage = usage.entry(user.to_string()).or_insert(Usage {
+            active: 0,
+            bytes: 0,
+            window_start: now,
+        });
+        if quota
+            .window
+            .is_some_and(|window| now.duration_since(usage.window_start) >= window)
+        {
+            usage.bytes = 0;
+            usage.window_start = now;
+        }
+        f(quota, usage)
+    }
+}
+
+impl QuotaStore for MemoryQuotaStore {
+    fn acquire<'a>(&'a self, user: &'a str) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        let acquired = self.with_usage(user, |quota, usage| {
+            if quota.max_connections.is_some_and(|max| usage.active >= max)
+                || quota.max_bytes.is_some_and(|max| usage.bytes >= max)
+            {
+                return false;
+            }
+            usage.active += 1;
+            true
+        });
+        Box::pin(async move { acquired })
+    }
+
+    fn release(&self, user: &str) {
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(usage) = usage.get_mut(user) {
+            usage.active = usage.active.saturating_sub(1);
+        }
+    }
+
+    fn consume<'a>(
+        &'a self,
+        user: &'a str,
+        bytes: u64,
+    ) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        let within = self.with_usage(user, |quota, usage| {
+            usage.bytes += bytes;
+            quota.max_bytes.is_none_or(|max| usage.bytes <= max)
+        });
+        Box::pin(async move { within })
+    }
+}
+
+// One open tunnel of a user, released when dropped.
+#[derive(Debug)]
+pub(crate) struct QuotaLease {
+    store: Arc<dyn QuotaStore>,
+    user: String,
+    reported: AtomicU64,
+}
+
+impl QuotaLease {
+    pub(crate) async fn acquire(
+        store: Option<&Arc<dyn QuotaStore>>,
+        user: Option<&str>,
+    ) -> Result<Option<Self>, ProxyStreamError> {
+        let (Some(store), Some(user)) = (store, user) else {
+            return Ok(None);
+        };
+        if !store.acquire(user).await {
+            return Err(ProxyStreamError::QuotaExceeded);
+        }
+        Ok(Some(QuotaLease {
+            store: store.clone(),
+            user: user.to_string(),
+            reported: AtomicU64::new(0),
+        }))
+    }
+
+    // Charges whatever `total` adds to what was already reported.
+    pub(crate) async fn report(&self, total: u64) -> bool {
+        let reported = self.reported.swap(total, Ordering::Relaxed);
+        if total <= reported {
+            return true;
+        }
+        self.store.consume(&self.user, total - reported).await
+    }
+}
+
+impl Drop for QuotaLease {
+    fn drop(&mut self) {
+        self.store.release(&self.user);
+    }
+}
+
+// Resolves once a relay has used up its quota; never without one.
+pub(crate) async fn enforce(quota: Option<&QuotaLease>, counters: &Counters) -> ProxyStreamError {
+    let Some(quota) = quota else {
+        return std::future::pending().await;
+    };
+    let mut interval = tokio::time::interval(REPORT_INTERVAL);
+    loop {
+        interval.tick().await;
+        if !quota.report(counters.read() + counters.written()).await {
+            return ProxyStreamError::QuotaExceeded;
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    fmt, io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    error::{ProxyStreamError, TimeoutKind},
+    timeout::is_idle_elapsed,
+    trace::Span,
+    DestinationAddress, PeerInfo,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    Socks5,
+    Socks5Udp,
+    HttpConnect,
+    Http,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    // The client finished sending first; the upstream then finished too.
+    ClientEof,
+    // The upstream finished sending first; the client then finished too.
+    UpstreamEof,
+    Reset,
+    Timeout(TimeoutKind),
+    QuotaExceeded,
+    Error(io::ErrorKind),
+    // The relay was dropped before it ended, e.g. when its task was aborted.
+    Aborted,
+}
+
+impl From<&io::Error> for CloseReason {
+    fn from(error: &io::Error) -> Self {
+        if is_idle_elapsed(error) {
+            return CloseReason::Timeout(TimeoutKind::Idle);
+        }
+        match error.kind() {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => CloseReason::Reset,
+            kind => CloseReason::Error(kind),
+        }
+    }
+}
+
+impl From<&ProxyStreamError> for CloseReason {
+    fn from(error: &ProxyStreamError) -> Self {
+        match error {
+            ProxyStreamError::Timeout(kind) => CloseReason::Timeout(*kind),
+            ProxyStreamError::QuotaExceeded => CloseReason::QuotaExceeded,
+            ProxyStreamError::Relay(outcome) => outcome.close_reason,
+            ProxyStreamError::IO(e) | ProxyStreamError::Upstream { source: e, .. } => e.into(),
+            _ => CloseReason::Error(io::ErrorKind::Other),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionRecord {
+    pub protocol: ProxyProtocol,
+    pub peer: PeerInfo,
+    pub destination: DestinationAddress,
+    pub upstream: Option<DestinationAddress>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub close_reason: CloseReason,
+}
+
+pub trait RecordSink: Send + Sync + 'static {
+    fn record(&self, record: ConnectionRecord);
+}
+
+impl<F> RecordSink for F
+where
+    F: Fn(ConnectionRecord) + Send + Sync + 'static,
+{
+    fn record(&self, record: ConnectionRecord) {
+        self(record)
+    }
+}
+
+impl fmt::Debug for dyn RecordSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RecordSink")
+    }
+}
+
+// Everything about a relay known before it starts; completed into a
+// `ConnectionRecord` once the relay ends.
+pub(crate) struct PendingRecord {
+    pub(crate) sink: Option<Arc<dyn RecordSink>>,
+    pub(crate) protocol: ProxyProtocol,
+    pub(crate) peer: PeerInfo,
+    pub(crate) destination: DestinationAddress,
+    pub(crate) upstream: Option<DestinationAddress>,
+    pub(crate) start: SystemTime,
+    pub(crate) span: Span,
+}
+
+impl PendingRecord {
+    // Counts the relay as active until the returned record is finished or
+    // dropped, reading the bytes moved so far from `counters`.
+    pub(crate) fn open(self, counters: Arc<Counters>, side: Side) -> OpenRecord {
+        crate::metrics::tunnel_opened(self.protocol);
+        OpenRecord {
+            pending: Some(self),
+            counters,
+            side,
+        }
+    }
+}
+
+// An end of a relay: the one a failure came from, or the one counters wrap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Upstream,
+}
+
+impl Side {
+    pub(crate) fn other(self) -> Side {
+        match self {
+            Side::Client => Side::Upstream,
+            Side::Upstream => Side::Client,
+        }
+    }
+}
+
+pub(crate) struct OpenRecord {
+    pending: Option<PendingRecord>,
+    counters: Arc<Counters>,
+    side: Side,
+}
+
+impl OpenRecord {
+    pub(crate) fn finish(mut self, close_reason: CloseReason) {
+        self.close(close_reason);
+    }
+
+    fn close(&mut self, close_reason: CloseReason) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        let (bytes_up, bytes_down) = match self.side {
+            Side::Client => (self.counters.read(), self.counters.written()),
+            Side::Upstream => (self.counters.written(), self.counters.read()),
+        };
+        crate::metrics::tunnel_closed(pending.protocol, bytes_up, bytes_down);
+        pending.span.closed(bytes_up, bytes_down, close_reason);
+        let Some(sink) = pending.sink else {
+            return;
+        };
+        sink.record(ConnectionRecord {
+            protocol: pending.protocol,
+            peer: pending.peer,
+            destination: pending.destination,
+            upstream: pending.upstream,
+            bytes_up,
+            bytes_down,
+            start: pending.start,
+            end: SystemTime::now(),
+            close_reason,
+        });
+    }
+}
+
+impl Drop for OpenRecord {
+    fn drop(&mut self) {
+        self.close(CloseReason::Aborted);
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn read(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add_read(&self, n: usize) {
+        self.read.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_written(&self, n: usize) {
+        self.written.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+// Counts the bytes read from and written to `T`; the counters are shared so
+// they stay readable after the stream is consumed or fails.
+pub(crate) struct Counted<T> {
+    inner: T,
+    counters: Arc<Counters>,
+}
+
+impl<T> Counted<T> {
+    pub(crate) fn new(inner: T, counters: Arc<Counters>) -> Self {
+        Self { inner, counters }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            self.counters.add_read(buf.filled().len() - filled);
+        }
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.counters.add_written(n);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.counters.add_written(n);
+        }
+        poll
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
@@ -0,0 +1,256 @@
+use std::{future::Future, io, sync::Arc};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    error::{ConfigError, ProxyStreamError, TimeoutKind},
+    limit::Throttle,
+    quota::{enforce, QuotaLease},
+    record::{CloseReason, Counted, Counters, PendingRecord, Side},
+    timeout::{Activity, IdleTimeout, Timeouts},
+    AsyncSocket,
+};
+
+// Same as tokio's `copy_bidirectional`.
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct BufferSizes {
+    pub upload: usize,
+    pub download: usize,
+}
+
+impl Default for BufferSizes {
+    fn default() -> Self {
+        BufferSizes {
+            upload: DEFAULT_BUFFER_SIZE,
+            download: DEFAULT_BUFFER_SIZE,
+        }
+    }
+}
+
+impl BufferSizes {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if self.upload == 0 || self.download == 0 {
+            return Err(ConfigError::ZeroBufferSize);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayOutcome {
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub close_reason: CloseReason,
+    // The end whose I/O failed, unless the relay ended with an EOF or was
+    // stopped by the proxy.
+    pub failed: Option<Side>,
+}
+
+impl RelayOutcome {
+    // Fails unless the relay ended with one of the sides closing.
+    pub fn into_result(self) -> Result<Self, ProxyStreamError> {
+        match self.close_reason {
+            CloseReason::ClientEof | CloseReason::UpstreamEof => Ok(self),
+            CloseReason::Timeout(kind) => Err(ProxyStreamError::Timeout(kind)),
+            CloseReason::QuotaExceeded => Err(ProxyStreamError::QuotaExceeded),
+            CloseReason::Reset | CloseReason::Error(_) | CloseReason::Aborted => {
+                Err(ProxyStreamError::Relay(self))
+            }
+        }
+    }
+}
+
+// Relays between `client` and `upstream` until both sides have closed,
+// passing each side's EOF on to the other as a write shutdown.
+pub async fn relay(
+    client: impl AsyncSocket,
+    upstream: impl AsyncSocket,
+    timeouts: &Timeouts,
+    buffers: &BufferSizes,
+) -> RelayOutcome {
+    Tunnel {
+        timeouts: *timeouts,
+        buffers: *buffers,
+        ..Default::default()
+    }
+    .relay(client, upstream)
+    .await
+}
+
+// Everything a server-side relay applies besides the two sockets.
+#[derive(Default)]
+pub(crate) struct Tunnel {
+    pub(crate) timeouts: Timeouts,
+    pub(crate) buffers: BufferSizes,
+    pub(crate) record: Option<PendingRecord>,
+    pub(crate) throttle: Throttle,
+    pub(crate) quota: Option<QuotaLease>,
+    pub(crate) zero_copy: bool,
+}
+
+impl Tunnel {
+    // The record, if any, is completed with the bytes moved so far however
+    // the relay ends.
+    pub(crate) async fn relay(
+        self,
+        client: impl AsyncSocket,
+        upstream: impl AsyncSocket,
+    ) -> RelayOutcome {
+        let counters = Arc::new(Counters::default());
+        let record = self
+            .record
+            .map(|record| record.open(counters.clone(), Side::Client));
+        let copy = copy(
+            client,
+            upstream,
+            &self.timeouts,
+            &self.buffers,
+            self.throttle,
+            self.zero_copy,
+            &counters,
+        );
+        let enforced = async {
+            tokio::select! {
+                ended = copy => ended,
+                _ = enforce(self.quota.as_ref(), &counters) => (CloseReason::QuotaExceeded, None),
+            }
+        };
+        let (close_reason, failed) = match self.timeouts.lifetime {
+            Some(lifetime) => tokio::time::timeout(lifetime, enforced)
+                .await
+                .unwrap_or((CloseReason::Timeout(TimeoutKind::Lifetime), None)),
+            None => enforced.await,
+        };
+        if let Some(quota) = &self.quota {
+            quota.report(counters.read() + counters.written()).await;
+        }
+        let outcome = RelayOutcome {
+            bytes_up: counters.read(),
+            bytes_down: counters.written(),
+            close_reason,
+            failed,
+        };
+        if let Some(record) = record {
+            record.finish(close_reason);
+        }
+        outcome
+    }
+}
+
+async fn copy(
+    client: impl AsyncSocket,
+    upstream: impl AsyncSocket,
+    timeouts: &Timeouts,
+    buffers: &BufferSizes,
+    throttle: Throttle,
+    zero_copy: bool,
+    counters: &Arc<Counters>,
+) -> (CloseReason, Option<Side>) {
+    // Shaped relays need the bytes in userspace to meter them.
+    #[cfg(target_os = "linux")]
+    let (client, upstream) = if zero_copy && throttle.is_empty() {
+        match crate::splice::tcp_pair(client, upstream) {
+            Ok((client, upstream)) => {
+                return crate::splice::relay(&client, &upstream, timeouts, counters).await
+            }
+            Err(sockets) => sockets,
+        }
+    } else {
+        (client, upstream)
+    };
+    #[cfg(not(target_os = "linux"))]
+    let _ = zero_copy;
+    let activity = Activity::new();
+    let client = IdleTimeout::shared(
+        throttle.client(Counted::new(client, counters.clone())),
+        timeouts.idle,
+        activity.clone(),
+    );
+    let upstream = IdleTimeout::shared(upstream, timeouts.idle, activity);
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+    both_ways(
+        pipe(
+            &mut client_read,
+            &mut upstream_write,
+            buffers.upload,
+            Side::Client,
+        ),
+        pipe(
+            &mut upstream_read,
+            &mut client_write,
+            buffers.download,
+            Side::Upstream,
+        ),
+    )
+    .await
+}
+
+// An I/O error of the relay and the end it came from, when known.
+pub(crate) struct Failure(Option<Side>, io::Error);
+
+impl From<io::Error> for Failure {
+    fn from(error: io::Error) -> Self {
+        Failure(None, error)
+    }
+}
+
+pub(crate) fn at(side: Side) -> impl Fn(io::Error) -> Failure {
+    move |error| Failure(Some(side), error)
+}
+
+// Copies until `reader`, the `from` end, reaches EOF, then shuts down
+// `writer` so the other side sees the half-close.
+async fn pipe(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    buffer_size: usize,
+    from: Side,
+) -> Result<(), Failure> {
+    let mut buf = vec![0; buffer_size];
+    loop {
+        let n = reader.read(&mut buf).await.map_err(at(from))?;
+        if n == 0 {
+            return writer.shutdown().await.map_err(at(from.other()));
+        }
+        writer
+            .write_all(&buf[..n])
+            .await
+            .map_err(at(from.other()))?;
+        writer.flush().await.map_err(at(from.other()))?;
+    }
+}
+
+// Drives both directions until each has closed, naming the side whose EOF
+// came first, or stops at the first failure and names its end.
+pub(crate) async fn both_ways(
+    upload: impl Future<Output = Result<(), Failure>>,
+    download: impl Future<Output = Result<(), Failure>>,
+) -> (CloseReason, Option<Side>) {
+    tokio::pin!(upload, download);
+    let (first, rest) = tokio::select! {
+        result = &mut upload => (
+            result.map(|_| CloseReason::ClientEof),
+            futures::future::Either::Left(&mut download),
+        ),
+        result = &mut download => (
+            result.map(|_| CloseReason::UpstreamEof),
+            futures::future::Either::Right(&mut upload),
+        ),
+    };
+    let reason = match first {
+        Ok(reason) => reason,
+        Err(Failure(side, e)) => return ((&e).into(), side),
+    };
+    match rest.await {
+        Ok(()) => (reason, None),
+        Err(Failure(side, e)) => ((&e).into(), side),
+    }
+}
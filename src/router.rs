@@ -0,0 +1,295 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use crate::{
+    error::ProxyStreamError, peer::canonical, AsyncSocket, Chain, DestinationAddress, PeerInfo,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl From<u16> for PortRange {
+    fn from(port: u16) -> Self {
+        PortRange {
+            start: port,
+            end: port,
+        }
+    }
+}
+
+impl From<std::ops::RangeInclusive<u16>> for PortRange {
+    fn from(range: std::ops::RangeInclusive<u16>) -> Self {
+        PortRange {
+            start: *range.start(),
+            end: *range.end(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Matcher {
+    Any,
+    Domain(String),
+    DomainSuffix(String),
+    Wildcard(String),
+    Regex(#[cfg_attr(feature = "serde", serde(with = "regex_str"))] regex::Regex),
+    // Only literal IP destinations match, so a hostname that resolves into
+    // the range slips past it unless routed with `Router::route_resolved`.
+    Cidr(IpNet),
+    Port(PortRange),
+    Source(IpNet),
+    User(String),
+}
+
+impl Matcher {
+    pub fn regex(pattern: &str) -> Result<Self, regex::Error> {
+        regex::Regex::new(pattern).map(Matcher::Regex)
+    }
+
+    pub fn matches(&self, addr: &DestinationAddress, peer: &PeerInfo) -> bool {
+        self.matches_resolved(addr, &[], peer)
+    }
+
+    // `resolved` holds what a domain destination was looked up to.
+    fn matches_resolved(
+        &self,
+        addr: &DestinationAddress,
+        resolved: &[IpAddr],
+        peer: &PeerInfo,
+    ) -> bool {
+        let domain = match addr {
+            DestinationAddress::Domain(domain, _) => Some(domain.trim_end_matches('.')),
+            DestinationAddress::Ip(_) => None,
+        };
+        let port = match addr {
+            DestinationAddress::Domain(_, port) => *port,
+            DestinationAddress::Ip(addr) => addr.port(),
+        };
+        match self {
+            Matcher::Any => true,
+            Matcher::Domain(name) => domain.is_some_and(|d| d.eq_ignore_ascii_case(name)),
+            Matcher::DomainSuffix(suffix) => domain.is_some_and(|d| {
+                let suffix = suffix.trim_start_matches('.');
+                // Compared as bytes: lossily decoded names need not split on
+                // a char boundary where the suffix starts.
+                let (d, suffix) = (d.as_bytes(), suffix.as_bytes());
+                d.len() >= suffix.len()
+                    && d[d.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+                    && (d.len() == suffix.len() || d[d.len() - suffix.len() - 1] == b'.')
+            }),
+            Matcher::Wildcard(pattern) => {
+                domain.is_some_and(|d| wildcard(pattern.as_bytes(), d.as_bytes()))
+            }
+            Matcher::Regex(regex) => domain.is_some_and(|d| regex.is_match(d)),
+            Matcher::Cidr(net) => match addr {
+                DestinationAddress::Ip(addr) => net.contains(&canonical(addr.ip())),
+                DestinationAddress::Domain(..) => {
+                    resolved.iter().any(|ip| net.contains(&canonical(*ip)))
+                }
+            },
+            Matcher::Port(range) => range.contains(port),
+            Matcher::Source(net) => peer.ip().is_some_and(|ip| net.contains(&ip)),
+            Matcher::User(user) => peer.user.as_ref() == Some(user),
+        }
+    }
+}
+
+// Case-insensitive glob where `*` matches any run of characters and `?` one.
+fn wildcard(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == b'?' || c.eq_ignore_ascii_case(&text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) => {
+                    backtrack = Some((bp, bt + 1));
+                    p = bp + 1;
+                    t = bt + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Action {
+    Direct,
+    Upstream(Chain),
+    Reject,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rule {
+    // Every matcher has to match for the rule to apply.
+    pub matchers: Vec<Matcher>,
+    pub action: Action,
+}
+
+impl Rule {
+    pub fn new(action: Action) -> Self {
+        Rule {
+            matchers: Vec::new(),
+            action,
+        }
+    }
+
+    pub fn matcher(mut self, matcher: Matcher) -> Self {
+        self.matchers.push(matcher);
+        self
+    }
+
+    pub fn matches(&self, addr: &DestinationAddress, peer: &PeerInfo) -> bool {
+        self.matches_resolved(addr, &[], peer)
+    }
+
+    fn matches_resolved(
+        &self,
+        addr: &DestinationAddress,
+        resolved: &[IpAddr],
+        peer: &PeerInfo,
+    ) -> bool {
+        self.matchers
+            .iter()
+            .all(|m| m.matches_resolved(addr, resolved, peer))
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Router {
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rules: Vec<Rule>,
+    pub default: Action,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new(Action::Direct)
+    }
+}
+
+impl Router {
+    pub fn new(default: Action) -> Self {
+        Router {
+            rules: Vec::new(),
+            default,
+        }
+    }
+
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn route(&self, addr: &DestinationAddress, peer: &PeerInfo) -> &Action {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(addr, peer))
+            .map(|rule| &rule.action)
+            .unwrap_or(&self.default)
+    }
+
+    // Like `route`, but domain destinations are looked up first so `Cidr`
+    // rules also see every address they resolve to. A failed lookup leaves
+    // them to the other rules, as in `route`.
+    pub async fn route_resolved(&self, addr: &DestinationAddress, peer: &PeerInfo) -> &Action {
+        let resolved: Vec<IpAddr> = match addr {
+            DestinationAddress::Domain(domain, port) => {
+                match tokio::net::lookup_host((domain.as_str(), *port)).await {
+                    Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+                    Err(_) => Vec::new(),
+                }
+            }
+            DestinationAddress::Ip(_) => Vec::new(),
+        };
+        self.route_among(addr, &resolved, peer)
+    }
+
+    // `resolved` holds what a domain destination was looked up to.
+    pub(crate) fn route_among(
+        &self,
+        addr: &DestinationAddress,
+        resolved: &[IpAddr],
+        peer: &PeerInfo,
+    ) -> &Action {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches_resolved(addr, resolved, peer))
+            .map(|rule| &rule.action)
+            .unwrap_or(&self.default)
+    }
+}
+
+impl Action {
+    pub(crate) async fn connect(
+        &self,
+        addr: &DestinationAddress,
+        connect_timeout: Option<std::time::Duration>,
+    ) -> Result<Box<dyn AsyncSocket>, ProxyStreamError> {
+        match self {
+            Action::Direct => crate::timeout::connect(addr, connect_timeout)
+                .await
+                .map(|stream| Box::new(stream) as Box<dyn AsyncSocket>)
+                .map_err(|e| ProxyStreamError::upstream(addr, e)),
+            Action::Upstream(chain) => chain.connect(addr).await,
+            Action::Reject => Err(ProxyStreamError::NotAllowed),
+        }
+    }
+
+    pub(crate) fn first_hop(&self) -> Option<&DestinationAddress> {
+        match self {
+            Action::Upstream(chain) => chain.hops().first().map(|hop| hop.addr()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+mod regex_str {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        regex: &regex::Regex,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(regex.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<regex::Regex, D::Error> {
+        regex::Regex::new(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,295 @@
+use std::net::{IpAddr, SocketAddr};
+
+use ipnet::IpNet;
+
+use crate::{
+    address::ToSocketDestination,
+    error::{ConfigError, ProxyStreamError},
+    Action, AsyncSocket, Chain, DestinationAddress, Matcher, PeerInfo, PortRange, ProxySpec, Rule,
+};
+
+static DIRECT: Action = Action::Direct;
+
+// Decides how a client reaches a destination: the first matching rule wins,
+// then the NO_PROXY exclusions, then the proxy configured for the scheme,
+// falling back to ALL_PROXY and finally to a direct connection.
+#[derive(Debug, Clone, Default)]
+pub struct ProxySelector {
+    rules: Vec<Rule>,
+    no_proxy: Vec<Rule>,
+    http: Option<Action>,
+    https: Option<Action>,
+    all: Option<Action>,
+}
+
+impl ProxySelector {
+    pub fn new() -> Self {
+        ProxySelector::default()
+    }
+
+    // Reads http_proxy, https_proxy, all_proxy and no_proxy, preferring the
+    // lowercase spelling like curl does.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let var = |name: &str| {
+            // A CGI request can set HTTP_PROXY through its Proxy header.
+            let cgi = name == "HTTP_PROXY" && var("REQUEST_METHOD").is_some();
+            var(&name.to_ascii_lowercase())
+                .or_else(|| if cgi { None } else { var(name) })
+                .filter(|value| !value.trim().is_empty())
+        };
+        let mut selector = ProxySelector::new();
+        if let Some(url) = var("HTTP_PROXY") {
+            selector = selector.http_proxy(parse_proxy(&url)?);
+        }
+        if let Some(url) = var("HTTPS_PROXY") {
+            selector = selector.https_proxy(parse_proxy(&url)?);
+        }
+        if let Some(url) = var("ALL_PROXY") {
+            selector = selector.all_proxy(parse_proxy(&url)?);
+        }
+        if let Some(list) = var("NO_PROXY") {
+            selector.no_proxy.extend(
+                list.split([',', ' '])
+                    .map(str::trim)
+                    .filter(|entry| !entry.is_empty())
+                    .map(no_proxy_rule),
+            );
+        }
+        Ok(selector)
+    }
+
+    // Consulted before anything else, in the order they were added.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn http_proxy(mut self, chain: Chain) -> Self {
+        self.http = Some(Action::Upstream(chain));
+        self
+    }
+
+    pub fn https_proxy(mut self, chain: Chain) -> Self {
+        self.https = Some(Action::Upstream(chain));
+        self
+    }
+
+    // Used for schemes without a proxy of their own.
+    pub fn all_proxy(mut self, chain: Chain) -> Self {
+        self.all = Some(Action::Upstream(chain));
+        self
+    }
+
+    // Destinations matching `matcher` are reached directly unless a rule
+    // says otherwise.
+    pub fn no_proxy(mut self, matcher: Matcher) -> Self {
+        self.no_proxy
+            .push(Rule::new(Action::Direct).matcher(matcher));
+        self
+    }
+
+    pub fn select(&self, scheme: &str, addr: &DestinationAddress) -> &Action {
+        let peer = PeerInfo::default();
+        if let Some(rule) = self.rules.iter().find(|rule| rule.matches(addr, &peer)) {
+            return &rule.action;
+        }
+        if self.no_proxy.iter().any(|rule| rule.matches(addr, &peer)) {
+            return &DIRECT;
+        }
+        let proxy = match scheme {
+            s if s.eq_ignore_ascii_case("http") || s.eq_ignore_ascii_case("ws") => &self.http,
+            s if s.eq_ignore_ascii_case("https") || s.eq_ignore_ascii_case("wss") => &self.https,
+            _ => &None,
+        };
+        proxy.as_ref().or(self.all.as_ref()).unwrap_or(&DIRECT)
+    }
+
+    pub async fn connect(
+        &self,
+        scheme: &str,
+        target: impl ToSocketDestination,
+    ) -> Result<Box<dyn AsyncSocket>, ProxyStreamError> {
+        let target = target.to_destination_address()?;
+        self.select(scheme, &target).connect(&target, None).await
+    }
+}
+
+fn parse_proxy(url: &str) -> Result<Chain, ConfigError> {
+    url.parse::<ProxySpec>()?.chain()
+}
+
+// NO_PROXY entries are `*`, IPs, CIDRs or domains that also cover their
+// subdomains, each optionally followed by a port.
+fn no_proxy_rule(entry: &str) -> Rule {
+    let rule = Rule::new(Action::Direct);
+    if entry == "*" {
+        return rule.matcher(Matcher::Any);
+    }
+    if let Ok(net) = entry.parse::<IpNet>() {
+        return rule.matcher(Matcher::Cidr(net));
+    }
+    if let Ok(ip) = entry.trim_matches(['[', ']']).parse::<IpAddr>() {
+        return rule.matcher(Matcher::Cidr(ip.into()));
+    }
+    if let Ok(addr) = entry.parse::<SocketAddr>() {
+        return rule
+            .matcher(Matcher::Cidr(addr.ip().into()))
+            .matcher(Matcher::Port(PortRange::from(addr.port())));
+    }
+    let (domain, port) = match entry.rsplit_once(':') {
+        Some((domain, port)) => match port.parse::<u16>() {
+            Ok(port) => (domain, Some(port)),
+            Err(_) => (entry, None),
+        },
+        None => (entry, None),
+    };
+    let rule = rule.matcher(Matcher::DomainSuffix(
+        domain
+            .trim_start_matches('*')
+            .trim_start_matches('.')
+            .to_string(),
+    ));
+    match port {
+        Some(port) => rule.matcher(Matcher::Port(port.into())),
+        None => rule,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn selector(vars: &[(&str, &str)]) -> ProxySelector {
+        let vars: HashMap<_, _> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        ProxySelector::from_vars(|name| vars.get(name).cloned()).unwrap()
+    }
+
+    // The proxy `target` is reached through, if any.
+    fn via(selector: &ProxySelector, scheme: &str, target: &str) -> Option<String> {
+        let action = selector.select(scheme, &target.parse().unwrap());
+        action.first_hop().map(ToString::to_string)
+    }
+
+    #[test]
+    fn prefers_lowercase_variables() {
+        let selector = selector(&[
+            ("http_proxy", "http://lower:3128"),
+            ("HTTP_PROXY", "http://upper:3128"),
+            ("HTTPS_PROXY", "http://secure:3128"),
+        ]);
+        assert_eq!(
+            via(&selector, "http", "example.com:80").unwrap(),
+            "lower:3128"
+        );
+        assert_eq!(
+            via(&selector, "https", "example.com:443").unwrap(),
+            "secure:3128"
+        );
+
+        // Set but empty counts as unset.
+        let selector = self::selector(&[("http_proxy", " "), ("all_proxy", "")]);
+        assert_eq!(via(&selector, "http", "example.com:80"), None);
+    }
+
+    #[test]
+    fn ignores_http_proxy_under_cgi() {
+        let selector = selector(&[("REQUEST_METHOD", "GET"), ("HTTP_PROXY", "http://evil:80")]);
+        assert_eq!(via(&selector, "http", "example.com:80"), None);
+
+        let selector = self::selector(&[
+            ("REQUEST_METHOD", "GET"),
+            ("HTTP_PROXY", "http://evil:80"),
+            ("http_proxy", "http://good:80"),
+            ("HTTPS_PROXY", "http://secure:80"),
+        ]);
+        assert_eq!(via(&selector, "http", "example.com:80").unwrap(), "good:80");
+        assert_eq!(
+            via(&selector, "https", "example.com:443").unwrap(),
+            "secure:80"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_all_proxy() {
+        let selector = selector(&[
+            ("https_proxy", "http://secure:3128"),
+            ("ALL_PROXY", "socks5h://all"),
+        ]);
+        assert_eq!(
+            via(&selector, "http", "example.com:80").unwrap(),
+            "all:1080"
+        );
+        assert_eq!(via(&selector, "WS", "example.com:80").unwrap(), "all:1080");
+        assert_eq!(via(&selector, "ftp", "example.com:21").unwrap(), "all:1080");
+        assert_eq!(
+            via(&selector, "wss", "example.com:443").unwrap(),
+            "secure:3128"
+        );
+
+        let selector = self::selector(&[("http_proxy", "http://plain:3128")]);
+        assert_eq!(
+            via(&selector, "ws", "example.com:80").unwrap(),
+            "plain:3128"
+        );
+        assert_eq!(via(&selector, "https", "example.com:443"), None);
+    }
+
+    #[test]
+    fn parses_no_proxy() {
+        let selector = selector(&[
+            ("all_proxy", "http://proxy:3128"),
+            (
+                "no_proxy",
+                "10.0.0.0/8, 192.168.1.5,[::1] .internal,*.corp,example.com:8080,1.2.3.4:443,,",
+            ),
+        ]);
+        let proxied = |target| via(&selector, "http", target).is_some();
+        assert!(!proxied("10.1.2.3:80"));
+        assert!(proxied("11.1.2.3:80"));
+        assert!(!proxied("192.168.1.5:80"));
+        assert!(proxied("192.168.1.6:80"));
+        assert!(!proxied("[::1]:80"));
+        assert!(!proxied("internal:80"));
+        assert!(!proxied("a.b.internal:80"));
+        assert!(proxied("notinternal:80"));
+        assert!(!proxied("corp:80"));
+        assert!(!proxied("www.corp:80"));
+        assert!(!proxied("example.com:8080"));
+        assert!(!proxied("www.example.com:8080"));
+        assert!(proxied("example.com:80"));
+        assert!(!proxied("1.2.3.4:443"));
+        assert!(proxied("1.2.3.4:80"));
+
+        let selector = self::selector(&[("all_proxy", "http://proxy:3128"), ("NO_PROXY", "*")]);
+        assert_eq!(via(&selector, "http", "example.com:80"), None);
+        assert_eq!(via(&selector, "http", "10.0.0.1:80"), None);
+    }
+
+    #[test]
+    fn rules_come_before_no_proxy() {
+        let selector = selector(&[("all_proxy", "http://proxy:3128"), ("no_proxy", "*")]).rule(
+            Rule::new(Action::Upstream(parse_proxy("http://ruled:3128").unwrap()))
+                .matcher(Matcher::DomainSuffix("example.com".to_string())),
+        );
+        assert_eq!(
+            via(&selector, "http", "example.com:80").unwrap(),
+            "ruled:3128"
+        );
+        assert_eq!(via(&selector, "http", "example.org:80"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_proxy_urls() {
+        let var = |name: &str| (name == "http_proxy").then(|| "ftp://proxy".to_string());
+        assert!(ProxySelector::from_vars(var).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use std::{fmt::Debug, future::Future, sync::Arc, time::Duration};
+
+use log::{debug, warn};
+use tokio::{
+    sync::{watch, Semaphore},
+    task::JoinSet,
+};
+
+use crate::{
+    error::ProxyStreamError,
+    http::ServerInterrupted,
+    socks5::{ServerInterruptedSocks5Stream, Socks5},
+    AsyncSocket, Http, HttpConfig, PeerInfo, SocksConfig,
+};
+
+// Accept errors other than a connection going away usually mean running out
+// of file descriptors, which retrying at once would only spin on.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+pub trait Listener: Send + 'static {
+    type Socket: AsyncSocket;
+    type Addr: Debug + Send + 'static;
+
+    fn accept(
+        &mut self,
+    ) -> impl Future<Output = std::io::Result<(Self::Socket, Self::Addr)>> + Send;
+
+    fn peer(_socket: &Self::Socket, _addr: &Self::Addr) -> PeerInfo {
+        PeerInfo::default()
+    }
+}
+
+impl Listener for tokio::net::TcpListener {
+    type Socket = tokio::net::TcpStream;
+    type Addr = std::net::SocketAddr;
+
+    async fn accept(&mut self) -> std::io::Result<(Self::Socket, Self::Addr)> {
+        tokio::net::TcpListener::accept(self).await
+    }
+
+    fn peer(_socket: &Self::Socket, addr: &Self::Addr) -> PeerInfo {
+        PeerInfo::new(*addr)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for tokio::net::UnixListener {
+    type Socket = tokio::net::UnixStream;
+    type Addr = tokio::net::unix::SocketAddr;
+
+    async fn accept(&mut self) -> std::io::Result<(Self::Socket, Self::Addr)> {
+        tokio::net::UnixListener::accept(self).await
+    }
+
+    fn peer(socket: &Self::Socket, _addr: &Self::Addr) -> PeerInfo {
+        socket
+            .peer_cred()
+            .map(PeerInfo::from_ucred)
+            .unwrap_or_default()
+    }
+}
+
+pub struct Server<L> {
+    listener: L,
+    max_connections: Option<usize>,
+    shutdown_timeout: Option<Duration>,
+}
+
+impl<L: Listener> Server<L> {
+    pub fn new(listener: L) -> Self {
+        Server {
+            listener,
+            max_connections: None,
+            shutdown_timeout: None,
+        }
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    pub async fn serve_socks5<F, Fut>(
+        self,
+        config: impl Into<Arc<SocksConfig>>,
+        handler: F,
+        shutdown: impl Future<Output = ()>,
+    ) where
+        F: Fn(ServerInterruptedSocks5Stream<L::Socket>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ProxyStreamError>> + Send,
+    {
+        let config = config.into();
+        let handler = Arc::new(handler);
+        self.run(shutdown, move |socket, addr, peer, _| {
+            let config = config.clone();
+            let handler = handler.clone();
+            async move {
+                let mut server = Socks5::new_server_with_peer(config, socket, peer);
+                let stream = match server.accept().await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!("{:?}: {}", addr, e);
+                        return;
+                    }
+                };
+                if let Err(e) = handler(stream).await {
+                    debug!("{:?}: {}", addr, e);
+                }
+            }
+        })
+        .await
+    }
+
+    pub async fn serve_http<F, Fut>(
+        self,
+        config: impl Into<Arc<HttpConfig>>,
+        handler: F,
+        shutdown: impl Future<Output = ()>,
+    ) where
+        F: Fn(ServerInterrupted) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ProxyStreamError>> + Send,
+    {
+        let config = config.into();
+        let handler = Arc::new(handler);
+        self.run(shutdown, move |socket, addr, peer, mut stopping| {
+            let stopped = async move {
+                let _ = stopping.changed().await;
+            };
+            let (mut http, connection) = Http::server(config.clone(), socket, peer, stopped);
+            let handler = handler.clone();
+            let requests = async move {
+                loop {
+                    let interrupted = match http.accept().await {
+                        Ok(interrupted) => interrupted,
+                        Err(ProxyStreamError::Closed) => return,
+                        Err(e) => {
+                            debug!("{:?}: {}", addr, e);
+                            return;
+                        }
+                    };
+                    if let Err(e) = handler(interrupted).await {
+                        debug!("{:?}: {}", addr, e);
+                    }
+                }
+            };
+            async move {
+                tokio::join!(connection, requests);
+            }
+        })
+        .await
+    }
+
+    pub async fn serve_socks5_direct(
+        self,
+        config: impl Into<Arc<SocksConfig>>,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        self.serve_socks5(
+            config,
+            ServerInterruptedSocks5Stream::serve_direct,
+            shutdown,
+        )
+        .await
+    }
+
+    pub async fn serve_http_direct(
+        self,
+        config: impl Into<Arc<HttpConfig>>,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        self.serve_http(config, ServerInterrupted::serve_direct, shutdown)
+            .await
+    }
+
+    async fn run<C, CF>(mut self, shutdown: impl Future<Output = ()>, connection: C)
+    where
+        C: Fn(L::Socket, L::Addr, PeerInfo, watch::Receiver<()>) -> CF,
+        CF: Future<Output = ()> + Send + 'static,
+    {
+        let limiter = self.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+        let mut tasks = JoinSet::new();
+        // Connections that can wind down on their own watch this; it changes
+        // when the server stops accepting.
+        let (stop, stopping) = watch::channel(());
+        let mut backoff = None;
+        tokio::pin!(shutdown);
+        loop {
+            let permit = match &limiter {
+                Some(limiter) => tokio::select! {
+                    permit = limiter.clone().acquire_owned() => permit.ok(),
+                    _ = &mut shutdown => break,
+                },
+                None => None,
+            };
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                _ = &mut shutdown => break,
+            };
+            while tasks.try_join_next().is_some() {}
+            match accepted {
+                Ok((socket, addr)) => {
+                    backoff = None;
+                    let peer = L::peer(&socket, &addr);
+                    let connection = connection(socket, addr, peer, stopping.clone());
+                    tasks.spawn(async move {
+                        connection.await;
+                        drop(permit);
+                    });
+                }
+                Err(e) if is_connection_error(&e) => debug!("{:?}", e),
+                Err(e) => {
+                    warn!("{:?}", e);
+                    let delay = backoff.map_or(MIN_ACCEPT_BACKOFF, |delay: Duration| {
+                        (delay * 2).min(MAX_ACCEPT_BACKOFF)
+                    });
+                    backoff = Some(delay);
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = &mut shutdown => break,
+                    }
+                }
+            }
+        }
+        drop(self.listener);
+        drop(stop);
+
+        let drain = async { while tasks.join_next().await.is_some() {} };
+        match self.shutdown_timeout {
+            Some(timeout) => {
+                if tokio::time::timeout(timeout, drain).await.is_err() {
+                    warn!(
+                        "Aborting {} connections after shutdown timeout",
+                        tasks.len()
+                    );
+                    tasks.shutdown().await;
+                }
+            }
+            None => drain.await,
+        }
+    }
+}
+
+// Errors about a single connection that failed before it was accepted.
+fn is_connection_error(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::Interrupted
+    )
+}
@@ -0,0 +1,63 @@
+// Client side of SOCKS4a, which only knows CONNECT and sends a user id in
+// place of credentials.
+
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    error::{socks::SocksError, ProxyStreamError},
+    AsyncSocket, DestinationAddress, ReplayStatus,
+};
+
+const VERSION: u8 = 4;
+const REPLY_VERSION: u8 = 0;
+const CONNECT: u8 = 1;
+const GRANTED: u8 = 90;
+const REJECTED: u8 = 91;
+
+pub(crate) async fn connect<T: AsyncSocket>(
+    mut socket: T,
+    addr: &DestinationAddress,
+    user: Option<&str>,
+) -> Result<T, ProxyStreamError> {
+    let user = user.unwrap_or_default();
+    let mut request = vec![VERSION, CONNECT];
+    request.extend_from_slice(&addr.port().to_be_bytes());
+    // 0.0.0.x with a non-zero x asks the proxy to resolve the name sent
+    // after the user id.
+    let domain = match addr {
+        DestinationAddress::Ip(SocketAddr::V4(addr)) => {
+            request.extend_from_slice(&addr.ip().octets());
+            None
+        }
+        DestinationAddress::Ip(SocketAddr::V6(_)) => Err(SocksError::InvalidAddress)?,
+        DestinationAddress::Domain(domain, _) => {
+            request.extend_from_slice(&[0, 0, 0, 1]);
+            Some(domain)
+        }
+    };
+    if user.contains('\0') || domain.is_some_and(|domain| domain.contains('\0')) {
+        Err(SocksError::InvalidAddress)?;
+    }
+    request.extend_from_slice(user.as_bytes());
+    request.push(0);
+    if let Some(domain) = domain {
+        request.extend_from_slice(domain.as_bytes());
+        request.push(0);
+    }
+    socket.write_all(&request).await?;
+    let mut reply = [0; 8];
+    socket.read_exact(&mut reply).await?;
+    match reply {
+        [REPLY_VERSION, GRANTED, ..] => Ok(socket),
+        [REPLY_VERSION, REJECTED, ..] => Err(SocksError::CommandFailed(
+            ReplayStatus::GeneralSocksServerFailure,
+        ))?,
+        // The remaining codes are identd failures.
+        [REPLY_VERSION, ..] => Err(SocksError::CommandFailed(
+            ReplayStatus::ConnectionNotAllowedByRuleset,
+        ))?,
+        _ => Err(SocksError::InvalidVersion)?,
+    }
+}
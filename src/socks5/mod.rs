@@ -1,9 +1,28 @@
 mod config;
-
-use std::net::SocketAddr;
-
-use crate::{address::ToSocketDestination, error::socks::SocksError, Protocol, ReplayStatus};
-pub use config::Config as SocksConfig;
+mod udp;
+
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+
+use crate::{
+    address::ToSocketDestination,
+    error::{socks::SocksError, TimeoutKind},
+    limit::Throttle,
+    metrics,
+    quota::QuotaLease,
+    record::{PendingRecord, ProxyProtocol},
+    relay::{BufferSizes, Tunnel},
+    timeout::{timeout, timeout_at},
+    tls::MaybeTls,
+    trace::Span,
+    Action, PeerInfo, Protocol, ReplayStatus, Router,
+};
+pub use config::{Config as SocksConfig, ConfigBuilder as SocksConfigBuilder};
+use log::info;
 
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
@@ -12,59 +31,202 @@ use crate::{address::DestinationAddress, error::ProxyStreamError, AsyncSocket};
 pub struct Socks5;
 
 pub struct Socks5Client<T> {
-    config: SocksConfig,
+    config: Arc<SocksConfig>,
     socket_stream: Option<T>,
 }
 
-#[allow(dead_code)]
 pub struct Socks5Server<T> {
-    config: SocksConfig,
+    config: Arc<SocksConfig>,
     socket_stream: Option<T>,
+    peer: PeerInfo,
+    start: SystemTime,
+    span: Span,
 }
 
 impl Socks5 {
     pub fn new_client(
-        config: SocksConfig,
+        config: impl Into<Arc<SocksConfig>>,
         socket_stream: impl AsyncSocket,
     ) -> Socks5Client<impl AsyncSocket> {
         Socks5Client {
-            config,
+            config: config.into(),
             socket_stream: Some(socket_stream),
         }
     }
-    pub fn new_server(
-        config: SocksConfig,
-        socket_stream: impl AsyncSocket,
-    ) -> Socks5Server<impl AsyncSocket> {
+    pub fn new_server<T: AsyncSocket>(
+        config: impl Into<Arc<SocksConfig>>,
+        socket_stream: T,
+    ) -> Socks5Server<T> {
+        Self::new_server_with_peer(config, socket_stream, PeerInfo::default())
+    }
+    pub fn new_server_with_peer<T: AsyncSocket>(
+        config: impl Into<Arc<SocksConfig>>,
+        socket_stream: T,
+        peer: impl Into<PeerInfo>,
+    ) -> Socks5Server<T> {
+        let peer = peer.into();
         Socks5Server {
-            config,
+            config: config.into(),
             socket_stream: Some(socket_stream),
+            span: Span::connection("socks5", &peer),
+            peer,
+            start: SystemTime::now(),
         }
     }
 }
 
 impl<T: AsyncSocket> Socks5Server<T> {
     pub async fn accept(&mut self) -> Result<ServerInterruptedSocks5Stream<T>, ProxyStreamError> {
-        let mut socket_stream = self.socket_stream.take().ok_or(ProxyStreamError::Closed)?;
-        let auth_request = AuthRequest::read(&mut socket_stream).await?;
-        if !auth_request.methods.contains(&AuthMethod::NoAuth) {
-            Err(SocksError::MethodNotSupported)?;
+        let socket_stream = self.socket_stream.take().ok_or(ProxyStreamError::Closed)?;
+        let timeouts = self.config.timeouts;
+        let allowed = self.config.acl.is_allowed(&self.peer);
+        if !allowed {
+            info!("Denied SOCKS5 client {:?} by ACL", self.peer.addr);
+            // Not worth a TLS handshake just to tell them.
+            if self.config.tls_acceptor.is_some() {
+                self.span.event("denied by ACL");
+                return Err(ProxyStreamError::NotAllowed);
+            }
         }
-        AuthResponse::new(Version::V5, AuthMethod::NoAuth)?
+        // TLS and SOCKS share one deadline so a slow client cannot stretch it.
+        let deadline = timeouts
+            .handshake
+            .map(|handshake| tokio::time::Instant::now() + handshake);
+        let tls = MaybeTls::accept(socket_stream, self.config.tls_acceptor.as_ref());
+        let mut socket_stream = timeout_at(deadline, TimeoutKind::Handshake, tls).await?;
+        // Denied clients are never authenticated; they negotiate no auth
+        // only to be told they are not allowed.
+        let authenticator = self.config.authenticator.as_ref().filter(|_| allowed);
+        let peer = &mut self.peer;
+        let span = self.span.clone();
+        let started = Instant::now();
+        let handshake = timeout_at(deadline, TimeoutKind::Handshake, async {
+            let auth = async {
+                let auth_request = AuthRequest::read(&mut socket_stream).await?;
+                let method = match authenticator {
+                    Some(_) => AuthMethod::UsernamePassword,
+                    None => AuthMethod::NoAuth,
+                };
+                if !auth_request.methods.contains(&method) {
+                    AuthResponse::new(Version::V5, AuthMethod::NoAcceptableMethod)?
+                        .write(&mut socket_stream)
+                        .await?;
+                    if !allowed {
+                        return Err(ProxyStreamError::NotAllowed);
+                    }
+                    Err(SocksError::MethodNotSupported)?;
+                }
+                AuthResponse::new(Version::V5, method)?
+                    .write(&mut socket_stream)
+                    .await?;
+                if let Some(authenticator) = authenticator {
+                    let credentials = PasswordRequest::read(&mut socket_stream).await?.into();
+                    let authenticated = authenticator.authenticate(&credentials, peer).await;
+                    PasswordResponse::write(&mut socket_stream, authenticated).await?;
+                    if !authenticated {
+                        info!("Rejected SOCKS5 client {:?} credentials", peer.addr);
+                        metrics::auth_failure(ProxyProtocol::Socks5);
+                        Err(SocksError::AuthenticationFailed)?;
+                    }
+                    span.record_user(&credentials.username);
+                    span.event("authenticated");
+                    peer.user = Some(credentials.username);
+                }
+                Ok::<_, ProxyStreamError>(())
+            };
+            span.auth().instrument(auth).await?;
+            let request = span
+                .negotiate()
+                .instrument(CommandRequest::read(&mut socket_stream))
+                .await?;
+            span.record_destination(&request.addr);
+            span.event("command received");
+            Ok::<_, ProxyStreamError>(request)
+        });
+        let request = self.span.instrument(handshake).await;
+        // A denied client that could not negotiate no auth is still a denial,
+        // not a failed handshake.
+        let denied = matches!(request, Err(ProxyStreamError::NotAllowed));
+        metrics::handshake(
+            ProxyProtocol::Socks5,
+            request.is_ok() || denied,
+            started.elapsed(),
+        );
+        let request = match request {
+            Err(ProxyStreamError::NotAllowed) => {
+                metrics::reply(
+                    ProxyProtocol::Socks5,
+                    ReplayStatus::ConnectionNotAllowedByRuleset,
+                );
+                self.span.event("denied by ACL");
+                return Err(ProxyStreamError::NotAllowed);
+            }
+            request => request.inspect_err(|e| self.span.failed("handshake failed", e))?,
+        };
+
+        if !allowed {
+            CommandResponse::new(
+                Version::V5,
+                Replay::ConnectionNotAllowedByRuleset,
+                DestinationAddress::default(),
+            )?
             .write(&mut socket_stream)
             .await?;
-        let request = CommandRequest::read(&mut socket_stream).await?;
+            metrics::reply(
+                ProxyProtocol::Socks5,
+                ReplayStatus::ConnectionNotAllowedByRuleset,
+            );
+            self.span.event("denied by ACL");
+            return Err(ProxyStreamError::NotAllowed);
+        }
 
-        let protocol = if matches!(request.command, Command::UdpAssociate) {
-            Protocol::Udp
-        } else {
-            Protocol::Tcp
+        let protocol = match request.command {
+            Command::Connect => Protocol::Tcp,
+            Command::UdpAssociate => Protocol::Udp,
+            Command::Bind => {
+                CommandResponse::new(
+                    Version::V5,
+                    Replay::CommandNotSupported,
+                    DestinationAddress::default(),
+                )?
+                .write(&mut socket_stream)
+                .await?;
+                metrics::reply(ProxyProtocol::Socks5, ReplayStatus::CommandNotSupported);
+                self.span.event("bind not supported");
+                Err(SocksError::CommandNotSupported)?
+            }
+        };
+
+        let quota =
+            QuotaLease::acquire(self.config.quota_store.as_ref(), self.peer.user.as_deref()).await;
+        let quota = match quota {
+            Ok(quota) => quota,
+            Err(e) => {
+                CommandResponse::new(
+                    Version::V5,
+                    Replay::ConnectionNotAllowedByRuleset,
+                    DestinationAddress::default(),
+                )?
+                .write(&mut socket_stream)
+                .await?;
+                metrics::reply(
+                    ProxyProtocol::Socks5,
+                    ReplayStatus::ConnectionNotAllowedByRuleset,
+                );
+                self.span.event("quota exceeded");
+                return Err(e);
+            }
         };
 
         Ok(ServerInterruptedSocks5Stream {
             protocol,
             addr: request.addr,
             socket: socket_stream,
+            config: self.config.clone(),
+            peer: self.peer.clone(),
+            start: self.start,
+            span: self.span.clone(),
+            quota,
         })
     }
 }
@@ -74,27 +236,62 @@ impl<T: AsyncSocket> Socks5Client<T> {
         &mut self,
         addr: impl ToSocketDestination,
     ) -> Result<ClientInterruptedSocks5Stream<T>, ProxyStreamError> {
-        let mut socket_stream = self.socket_stream.take().ok_or(ProxyStreamError::Closed)?;
-        AuthRequest::new(Version::V5, self.config.auth_method.clone())?
-            .write(&mut socket_stream)
-            .await?;
-        AuthResponse::read(&mut socket_stream).await?;
+        let mut addr = addr.to_destination_address()?;
+        if self.config.resolve_locally {
+            addr = addr
+                .resolve()
+                .await
+                .map_err(|e| ProxyStreamError::upstream(&addr, e))?
+                .into();
+        }
+        let socket_stream = self.socket_stream.take().ok_or(ProxyStreamError::Closed)?;
+        let timeouts = self.config.timeouts;
+        let tls = MaybeTls::connect(socket_stream, self.config.tls_connector.as_ref());
+        let mut socket_stream = timeout(timeouts.handshake, TimeoutKind::Handshake, tls).await?;
+        let auth_request = AuthRequest::new(Version::V5, self.config.auth_methods.clone())?;
+        let credentials = self.config.credentials.as_ref();
+        timeout(timeouts.handshake, TimeoutKind::Handshake, async {
+            auth_request.write(&mut socket_stream).await?;
+            match AuthResponse::read(&mut socket_stream).await?.method {
+                AuthMethod::NoAuth => Ok(()),
+                AuthMethod::UsernamePassword => {
+                    let credentials = credentials.ok_or(SocksError::MethodNotSupported)?;
+                    PasswordRequest::from(credentials)
+                        .write(&mut socket_stream)
+                        .await?;
+                    PasswordResponse::read(&mut socket_stream).await
+                }
+                _ => Err(SocksError::MethodNotSupported),
+            }
+        })
+        .await?;
 
         Ok(ClientInterruptedSocks5Stream {
-            addr: addr.to_destination_address()?,
+            addr,
             socket: socket_stream,
+            timeouts,
+            buffers: self.config.buffers,
+            zero_copy: self.config.zero_copy,
         })
     }
 }
 
 pub struct ClientInterruptedSocks5Stream<T> {
     addr: DestinationAddress,
-    socket: T,
+    socket: MaybeTls<T>,
+    timeouts: crate::Timeouts,
+    buffers: BufferSizes,
+    zero_copy: bool,
 }
 pub struct ServerInterruptedSocks5Stream<T> {
     protocol: crate::Protocol,
     addr: DestinationAddress,
-    socket: T,
+    socket: MaybeTls<T>,
+    config: Arc<SocksConfig>,
+    peer: PeerInfo,
+    start: SystemTime,
+    span: Span,
+    quota: Option<QuotaLease>,
 }
 
 impl<T: AsyncSocket> ClientInterruptedSocks5Stream<T> {
@@ -114,19 +311,30 @@ impl<T: AsyncSocket> ClientInterruptedSocks5Stream<T> {
     pub async fn proxied_stream(
         mut self,
     ) -> Result<impl crate::AsyncSocket, crate::error::ProxyStreamError> {
-        CommandRequest::new(Version::V5, Command::Connect, self.addr.to_owned())?
-            .write(&mut self.socket)
-            .await?;
-        CommandResponse::read(&mut self.socket).await?;
+        let request = CommandRequest::new(Version::V5, Command::Connect, self.addr.to_owned())?;
+        let response = timeout(self.timeouts.handshake, TimeoutKind::Handshake, async {
+            request.write(&mut self.socket).await?;
+            CommandResponse::read(&mut self.socket).await
+        })
+        .await?;
+        if response.replay != Replay::Succeeded {
+            Err(SocksError::CommandFailed(response.replay.into()))?;
+        }
 
         Ok(self.socket)
     }
-    pub async fn serve(self, mut socket_stream: impl AsyncSocket) -> Result<(), ProxyStreamError>
+    pub async fn serve(self, socket_stream: impl AsyncSocket) -> Result<(), ProxyStreamError>
     where
         Self: Sized,
     {
-        let mut s = self.proxied_stream().await?;
-        _ = tokio::io::copy_bidirectional(&mut s, &mut socket_stream).await?;
+        let tunnel = Tunnel {
+            timeouts: self.timeouts,
+            buffers: self.buffers,
+            zero_copy: self.zero_copy,
+            ..Default::default()
+        };
+        let s = self.proxied_stream().await?;
+        tunnel.relay(socket_stream, s).await.into_result()?;
         Ok(())
     }
 }
@@ -136,10 +344,15 @@ impl<T: AsyncSocket> ServerInterruptedSocks5Stream<T> {
         &self.addr
     }
 
+    pub fn peer(&self) -> &PeerInfo {
+        &self.peer
+    }
+
     pub async fn replay_error(
         mut self,
         error: crate::ReplayStatus,
     ) -> Result<(), ProxyStreamError> {
+        metrics::reply(self.proxy_protocol(), error);
         self.socket
             .write_all(&[Version::V5 as u8, (&Replay::from(error)).into(), 0])
             .await?;
@@ -152,20 +365,141 @@ impl<T: AsyncSocket> ServerInterruptedSocks5Stream<T> {
     pub async fn proxied_stream(
         mut self,
     ) -> Result<impl crate::AsyncSocket, crate::error::ProxyStreamError> {
+        metrics::reply(self.proxy_protocol(), ReplayStatus::Succeeded);
         CommandResponse::new(Version::V5, Replay::Succeeded, self.addr.to_owned())?
             .write(&mut self.socket)
             .await?;
 
         Ok(self.socket)
     }
-    pub async fn serve(self, mut socket_stream: impl AsyncSocket) -> Result<(), ProxyStreamError>
+    pub async fn serve(self, socket_stream: impl AsyncSocket) -> Result<(), ProxyStreamError>
     where
         Self: Sized,
     {
-        let mut s = self.proxied_stream().await?;
-        _ = tokio::io::copy_bidirectional(&mut s, &mut socket_stream).await?;
+        self.serve_upstream(socket_stream, None).await
+    }
+    async fn serve_upstream(
+        mut self,
+        socket_stream: impl AsyncSocket,
+        upstream: Option<DestinationAddress>,
+    ) -> Result<(), ProxyStreamError> {
+        let config = self.config.clone();
+        let span = self.span.clone();
+        span.upstream(upstream.as_ref());
+        let tunnel = Tunnel {
+            timeouts: config.timeouts,
+            buffers: config.buffers,
+            record: Some(self.pending_record(ProxyProtocol::Socks5, upstream)),
+            throttle: self.throttle(),
+            quota: self.quota.take(),
+            zero_copy: config.zero_copy,
+        };
+        let s = self.proxied_stream().await?;
+        let relay = tunnel.relay(s, socket_stream);
+        span.relay().instrument(relay).await.into_result()?;
         Ok(())
     }
+    fn proxy_protocol(&self) -> ProxyProtocol {
+        match self.protocol {
+            Protocol::Tcp => ProxyProtocol::Socks5,
+            Protocol::Udp => ProxyProtocol::Socks5Udp,
+        }
+    }
+    fn throttle(&self) -> Throttle {
+        self.config.shaper.throttle(
+            self.peer.user.as_deref(),
+            self.config.authenticator.as_ref(),
+        )
+    }
+    pub(crate) fn pending_record(
+        &self,
+        protocol: ProxyProtocol,
+        upstream: Option<DestinationAddress>,
+    ) -> PendingRecord {
+        PendingRecord {
+            sink: self.config.record_sink.clone(),
+            protocol,
+            peer: self.peer.clone(),
+            destination: self.addr.clone(),
+            upstream,
+            start: self.start,
+            span: self.span.clone(),
+        }
+    }
+    pub async fn serve_direct(self) -> Result<(), ProxyStreamError> {
+        if matches!(self.protocol, Protocol::Udp) {
+            return self.serve_association(None).await;
+        }
+        let addr = self.addr.clone();
+        let connect_timeout = self.config.timeouts.connect;
+        self.serve_connected(async {
+            let stream = crate::timeout::connect(&addr, connect_timeout)
+                .await
+                .map_err(|e| ProxyStreamError::upstream(&addr, e))?;
+            let upstream = stream.peer_addr().ok().map(DestinationAddress::Ip);
+            Ok((stream, upstream))
+        })
+        .await
+    }
+    pub async fn serve_with<S: AsyncSocket>(
+        self,
+        upstream: impl Future<Output = Result<S, ProxyStreamError>>,
+    ) -> Result<(), ProxyStreamError> {
+        self.serve_connected(async { Ok((upstream.await?, None)) })
+            .await
+    }
+    async fn serve_connected<S: AsyncSocket>(
+        self,
+        upstream: impl Future<Output = Result<(S, Option<DestinationAddress>), ProxyStreamError>>,
+    ) -> Result<(), ProxyStreamError> {
+        if matches!(self.protocol, Protocol::Udp) {
+            self.replay_error(ReplayStatus::CommandNotSupported).await?;
+            return Err(ProxyStreamError::NotImplemented);
+        }
+        let started = Instant::now();
+        let upstream = self.span.dial().instrument(upstream).await;
+        metrics::dial(upstream.is_ok(), started.elapsed());
+        match upstream {
+            Ok((socket, upstream)) => self.serve_upstream(socket, upstream).await,
+            Err(e) => {
+                self.span.failed("upstream failed", &e);
+                self.replay_error((&e).into()).await?;
+                Err(e)
+            }
+        }
+    }
+    pub async fn serve_action(self, action: &Action) -> Result<(), ProxyStreamError> {
+        match action {
+            Action::Direct => self.serve_direct().await,
+            _ => {
+                let addr = self.addr.clone();
+                let connect_timeout = self.config.timeouts.connect;
+                let first_hop = action.first_hop().cloned();
+                self.serve_connected(async {
+                    Ok((action.connect(&addr, connect_timeout).await?, first_hop))
+                })
+                .await
+            }
+        }
+    }
+    // Like `serve_action` with the route for the destination, but a UDP
+    // association also routes the target of every datagram.
+    pub async fn serve_router(self, router: &Router) -> Result<(), ProxyStreamError> {
+        if matches!(self.protocol, Protocol::Udp) {
+            return self.serve_association(Some(router)).await;
+        }
+        let action = router.route_resolved(&self.addr, &self.peer).await;
+        self.serve_action(action).await
+    }
+    async fn serve_association(self, router: Option<&Router>) -> Result<(), ProxyStreamError> {
+        match self.config.udp_bind {
+            Some(bind) => self.associate(bind, router).await,
+            None => {
+                self.replay_error(ReplayStatus::CommandNotSupported).await?;
+                Err(ProxyStreamError::NotImplemented)
+            }
+        }
+    }
     pub fn proto(&self) -> &crate::Protocol {
         &self.protocol
     }
@@ -185,6 +519,11 @@ impl Version {
     }
 }
 #[derive(PartialEq, Debug, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum AuthMethod {
     #[default]
     NoAuth,
@@ -429,6 +768,80 @@ impl AuthResponse {
     }
 }
 
+// Username/password sub-negotiation, RFC 1929.
+pub struct PasswordRequest {
+    username: String,
+    password: String,
+}
+
+impl From<&crate::Credentials> for PasswordRequest {
+    fn from(credentials: &crate::Credentials) -> Self {
+        PasswordRequest {
+            username: credentials.username.clone(),
+            password: credentials.password.clone(),
+        }
+    }
+}
+
+impl From<PasswordRequest> for crate::Credentials {
+    fn from(request: PasswordRequest) -> Self {
+        crate::Credentials::new(request.username, request.password)
+    }
+}
+
+impl PasswordRequest {
+    pub async fn read(mut reader: impl AsyncRead + Unpin) -> Result<Self, SocksError> {
+        if reader.read_u8().await? != 1 {
+            return Err(SocksError::InvalidVersion);
+        }
+        let mut username = vec![0; reader.read_u8().await? as usize];
+        reader.read_exact(&mut username).await?;
+        let mut password = vec![0; reader.read_u8().await? as usize];
+        reader.read_exact(&mut password).await?;
+        Ok(PasswordRequest {
+            username: String::from_utf8(username).map_err(|_| SocksError::AuthenticationFailed)?,
+            password: String::from_utf8(password).map_err(|_| SocksError::AuthenticationFailed)?,
+        })
+    }
+    pub async fn write(&self, mut writer: impl AsyncWrite + Unpin) -> Result<(), SocksError> {
+        writer
+            .write_all(
+                &[
+                    [1, self.username.len() as u8].as_ref(),
+                    self.username.as_bytes(),
+                    [self.password.len() as u8].as_ref(),
+                    self.password.as_bytes(),
+                ]
+                .concat(),
+            )
+            .await
+            .map_err(|e| e.into())
+    }
+}
+
+pub struct PasswordResponse;
+
+impl PasswordResponse {
+    pub async fn read(mut reader: impl AsyncRead + Unpin) -> Result<(), SocksError> {
+        if reader.read_u8().await? != 1 {
+            return Err(SocksError::InvalidVersion);
+        }
+        match reader.read_u8().await? {
+            0 => Ok(()),
+            _ => Err(SocksError::AuthenticationFailed),
+        }
+    }
+    pub async fn write(
+        mut writer: impl AsyncWrite + Unpin,
+        success: bool,
+    ) -> Result<(), SocksError> {
+        writer
+            .write_all(&[1, if success { 0 } else { 1 }])
+            .await
+            .map_err(|e| e.into())
+    }
+}
+
 pub struct CommandRequest {
     version: Version,
     pub command: Command,
@@ -514,6 +927,24 @@ impl CommandResponse {
     }
 }
 
+impl From<Replay> for ReplayStatus {
+    fn from(val: Replay) -> Self {
+        match val {
+            Replay::Succeeded => ReplayStatus::Succeeded,
+            Replay::ConnectionNotAllowedByRuleset => ReplayStatus::ConnectionNotAllowedByRuleset,
+            Replay::NetworkUnreachable => ReplayStatus::NetworkUnreachable,
+            Replay::HostUnreachable => ReplayStatus::HostUnreachable,
+            Replay::ConnectionRefused => ReplayStatus::ConnectionRefused,
+            Replay::TtlExpired => ReplayStatus::TtlExpired,
+            Replay::CommandNotSupported => ReplayStatus::CommandNotSupported,
+            Replay::AddressTypeNotSupported => ReplayStatus::AddressTypeNotSupported,
+            Replay::GeneralSocksServerFailure | Replay::Other(_) => {
+                ReplayStatus::GeneralSocksServerFailure
+            }
+        }
+    }
+}
+
 impl From<ReplayStatus> for Replay {
     fn from(val: ReplayStatus) -> Self {
         match val {
@@ -0,0 +1,204 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use log::debug;
+use tokio::{io::AsyncReadExt, net::UdpSocket};
+
+use super::{Address, CommandResponse, Replay, ServerInterruptedSocks5Stream, Version};
+use crate::{
+    error::{ProxyStreamError, TimeoutKind},
+    metrics,
+    peer::canonical,
+    quota::enforce,
+    record::{CloseReason, Counters, ProxyProtocol, Side},
+    timeout::timeout,
+    Action, AsyncSocket, DestinationAddress, ReplayStatus, Router,
+};
+
+const MAX_DATAGRAM: usize = 65535;
+
+impl<T: AsyncSocket> ServerInterruptedSocks5Stream<T> {
+    pub async fn serve_udp(self, bind: IpAddr) -> Result<(), ProxyStreamError> {
+        self.associate(bind, None).await
+    }
+
+    // With a router, datagrams are only relayed to targets it sends direct.
+    pub(crate) async fn associate(
+        mut self,
+        bind: IpAddr,
+        router: Option<&Router>,
+    ) -> Result<(), ProxyStreamError> {
+        let inbound = match UdpSocket::bind((bind, 0)).await {
+            Ok(inbound) => inbound,
+            Err(e) => {
+                self.replay_error((&e).into()).await?;
+                return Err(e.into());
+            }
+        };
+        metrics::reply(ProxyProtocol::Socks5Udp, ReplayStatus::Succeeded);
+        CommandResponse::new(
+            Version::V5,
+            Replay::Succeeded,
+            DestinationAddress::Ip(inbound.local_addr()?),
+        )?
+        .write(&mut self.socket)
+        .await?;
+
+        // Datagrams must come from the client that asked for the association;
+        // DST.ADDR only narrows down its port, or the address for clients
+        // without one, e.g. over a Unix socket.
+        let (dst_ip, expected_port) = match self.addr {
+            DestinationAddress::Ip(addr) => (
+                Some(canonical(addr.ip())).filter(|ip| !ip.is_unspecified()),
+                Some(addr.port()).filter(|port| *port != 0),
+            ),
+            DestinationAddress::Domain(..) => (None, None),
+        };
+        let expected_ip = self.peer.ip().or(dst_ip);
+        let lifetime = self.config.timeouts.lifetime;
+        let counters = Arc::new(Counters::default());
+        let record = self
+            .pending_record(ProxyProtocol::Socks5Udp, None)
+            .open(counters.clone(), Side::Client);
+        let counted = counters.clone();
+        let span = self.span.relay();
+        span.event("udp association started");
+        let quota = self.quota.take();
+        let peer = self.peer.clone();
+        let association = timeout(lifetime, TimeoutKind::Lifetime, async move {
+            let outbound_v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+            let mut outbound_v6: Option<UdpSocket> = None;
+            let mut client: Option<SocketAddr> = None;
+            let mut control = [0u8; 1];
+            let mut inbound_buf = vec![0u8; MAX_DATAGRAM];
+            let mut v4_buf = vec![0u8; MAX_DATAGRAM];
+            let mut v6_buf = vec![0u8; MAX_DATAGRAM];
+            loop {
+                tokio::select! {
+                    read = self.socket.read(&mut control) => {
+                        // The association lives as long as the control connection.
+                        if read? == 0 {
+                            return Ok::<_, ProxyStreamError>(());
+                        }
+                    }
+                    received = inbound.recv_from(&mut inbound_buf) => {
+                        let (len, from) = received?;
+                        if expected_ip.is_some_and(|ip| ip != canonical(from.ip()))
+                            || expected_port.is_some_and(|port| port != from.port())
+                            || client.is_some_and(|client| client != from)
+                        {
+                            continue;
+                        }
+                        let Some((target, payload)) = decode(&inbound_buf[..len]).await else {
+                            continue;
+                        };
+                        client = Some(from);
+                        let resolved = resolve(&target).await;
+                        let Some(&first) = resolved.first() else {
+                            continue;
+                        };
+                        if let Some(router) = router {
+                            let ips: Vec<IpAddr> = resolved.iter().map(SocketAddr::ip).collect();
+                            if !matches!(router.route_among(&target, &ips, &peer), Action::Direct) {
+                                debug!("Dropped UDP datagram to {} by route", target);
+                                continue;
+                            }
+                        }
+                        let target = first;
+                        let outbound = match target {
+                            SocketAddr::V4(_) => &outbound_v4,
+                            SocketAddr::V6(_) => match outbound_v6 {
+                                Some(ref outbound) => outbound,
+                                None => outbound_v6
+                                    .insert(UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?),
+                            },
+                        };
+                        match outbound.send_to(payload, target).await {
+                            Ok(sent) => counted.add_read(sent),
+                            Err(e) => debug!("{:?}", e),
+                        }
+                    }
+                    received = outbound_v4.recv_from(&mut v4_buf) => {
+                        let (len, from) = received?;
+                        reply(&inbound, client, from, &v4_buf[..len]).await?;
+                        counted.add_written(len);
+                    }
+                    received = async {
+                        match outbound_v6 {
+                            Some(ref outbound) => outbound.recv_from(&mut v6_buf).await,
+                            None => std::future::pending().await,
+                        }
+                    } => {
+                        let (len, from) = received?;
+                        reply(&inbound, client, from, &v6_buf[..len]).await?;
+                        counted.add_written(len);
+                    }
+                }
+            }
+        });
+        let enforced = async {
+            tokio::select! {
+                result = association => result,
+                error = enforce(quota.as_ref(), &counters) => Err(error),
+            }
+        };
+        let result = span.instrument(enforced).await;
+        if let Some(quota) = &quota {
+            quota.report(counters.read() + counters.written()).await;
+        }
+        let close_reason = match &result {
+            // The association ends when the client closes the control connection.
+            Ok(()) => CloseReason::ClientEof,
+            Err(e) => e.into(),
+        };
+        record.finish(close_reason);
+        result
+    }
+}
+
+async fn decode(datagram: &[u8]) -> Option<(DestinationAddress, &[u8])> {
+    // RSV(2) FRAG(1); fragmented datagrams are not supported and dropped.
+    if datagram.len() < 3 || datagram[2] != 0 {
+        return None;
+    }
+    let mut reader = &datagram[3..];
+    let address = Address::read(&mut reader).await.ok()?;
+    Some((address.addr, reader))
+}
+
+async fn resolve(addr: &DestinationAddress) -> Vec<SocketAddr> {
+    match addr {
+        DestinationAddress::Ip(addr) => vec![*addr],
+        DestinationAddress::Domain(domain, port) => {
+            match tokio::net::lookup_host((domain.as_str(), *port)).await {
+                Ok(addrs) => addrs.collect(),
+                Err(e) => {
+                    debug!("{:?}", e);
+                    Vec::new()
+                }
+            }
+        }
+    }
+}
+
+async fn reply(
+    inbound: &UdpSocket,
+    client: Option<SocketAddr>,
+    from: SocketAddr,
+    payload: &[u8],
+) -> Result<(), ProxyStreamError> {
+    let Some(client) = client else {
+        return Ok(());
+    };
+    let mut datagram = vec![0, 0, 0];
+    Address::from(&DestinationAddress::Ip(from))
+        .write(&mut datagram)
+        .await?;
+    datagram.extend_from_slice(payload);
+    if let Err(e) = inbound.send_to(&datagram, client).await {
+        debug!("{:?}", e);
+    }
+    Ok(())
+}
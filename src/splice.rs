@@ -0,0 +1,263 @@
+// Zero-copy relay between two TCP sockets: bytes move socket -> pipe ->
+// socket inside the kernel with splice(2), never entering userspace.
+
+use std::{
+    any::Any,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+    time::Duration,
+};
+
+use tokio::{io::Interest, net::TcpStream};
+
+use crate::{
+    record::{CloseReason, Counters, Side},
+    relay::{at, both_ways, Failure},
+    timeout::{idle_elapsed, Activity, Timeouts},
+    tls::MaybeTls,
+};
+
+// Default capacity of a Linux pipe.
+const PIPE_SIZE: usize = 1 << 16;
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors pipe2 writes.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: both descriptors were just created and are owned by nobody else.
+        Ok(unsafe {
+            Pipe {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            }
+        })
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: plain syscall on descriptors kept open by the caller.
+    let n = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+fn shutdown_write(socket: &TcpStream) -> io::Result<()> {
+    // SAFETY: plain syscall on a descriptor owned by `socket`.
+    if unsafe { libc::shutdown(socket.as_raw_fd(), libc::SHUT_WR) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::NotConnected {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// Moves bytes from `from`, the `side` end, to `to` until `from` reaches EOF,
+// then shuts down the write half of `to`.
+async fn copy(
+    from: &TcpStream,
+    to: &TcpStream,
+    side: Side,
+    idle: Option<Duration>,
+    activity: &Activity,
+    progress: impl Fn(usize),
+) -> Result<(), Failure> {
+    let pipe = Pipe::new()?;
+    loop {
+        // The pipe is drained before every read, so EAGAIN here always means
+        // the socket has nothing to read.
+        let read = from.async_io(Interest::READABLE, || {
+            splice(from.as_raw_fd(), pipe.write.as_raw_fd(), PIPE_SIZE)
+        });
+        tokio::pin!(read);
+        // Traffic the other way keeps this direction alive too.
+        let n = match idle {
+            Some(idle) => loop {
+                let deadline = activity.last() + idle;
+                match tokio::time::timeout_at(deadline, &mut read).await {
+                    Ok(n) => break n.map_err(at(side))?,
+                    Err(_) if activity.last() + idle > tokio::time::Instant::now() => continue,
+                    Err(_) => return Err(at(side)(idle_elapsed())),
+                }
+            },
+            None => read.await.map_err(at(side))?,
+        };
+        activity.touch();
+        if n == 0 {
+            break;
+        }
+        let mut pending = n;
+        while pending > 0 {
+            let written = to
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), to.as_raw_fd(), pending)
+                })
+                .await
+                .map_err(at(side.other()))?;
+            pending -= written;
+            progress(written);
+        }
+    }
+    shutdown_write(to).map_err(at(side.other()))
+}
+
+// Hands both sockets back unless they are both plain TCP streams.
+pub(crate) fn tcp_pair<A: Any, B: Any>(
+    client: A,
+    upstream: B,
+) -> Result<(TcpStream, TcpStream), (A, B)> {
+    if !is_tcp(&client) || !is_tcp(&upstream) {
+        return Err((client, upstream));
+    }
+    let client = take_tcp(&mut Some(client));
+    let upstream = take_tcp(&mut Some(upstream));
+    Ok(client.zip(upstream).expect("checked to be TCP"))
+}
+
+// Also accepts a server or client socket that did not negotiate TLS.
+fn is_tcp(socket: &dyn Any) -> bool {
+    socket.is::<TcpStream>()
+        || socket
+            .downcast_ref::<MaybeTls<TcpStream>>()
+            .is_some_and(MaybeTls::is_plain)
+}
+
+fn take_tcp(socket: &mut dyn Any) -> Option<TcpStream> {
+    if let Some(socket) = socket.downcast_mut::<Option<TcpStream>>() {
+        return socket.take();
+    }
+    socket
+        .downcast_mut::<Option<MaybeTls<TcpStream>>>()?
+        .take()?
+        .into_plain()
+        .ok()
+}
+
+pub(crate) async fn relay(
+    client: &TcpStream,
+    upstream: &TcpStream,
+    timeouts: &Timeouts,
+    counters: &Counters,
+) -> (CloseReason, Option<Side>) {
+    let activity = Activity::new();
+    both_ways(
+        copy(
+            client,
+            upstream,
+            Side::Client,
+            timeouts.idle,
+            &activity,
+            |n| counters.add_read(n),
+        ),
+        copy(
+            upstream,
+            client,
+            Side::Upstream,
+            timeouts.idle,
+            &activity,
+            |n| counters.add_written(n),
+        ),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::error::TimeoutKind;
+
+    // Both ends of a loopback connection.
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let near = TcpStream::connect(listener.local_addr().unwrap());
+        let (near, far) = tokio::join!(near, listener.accept());
+        (near.unwrap(), far.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn relays_and_passes_on_half_close() {
+        let (mut client, client_side) = pair().await;
+        let (upstream_side, mut upstream) = pair().await;
+        let (timeouts, counters) = (Timeouts::default(), Counters::default());
+        let relay = relay(&client_side, &upstream_side, &timeouts, &counters);
+        let peers = async {
+            client.write_all(b"request").await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut request = Vec::new();
+            upstream.read_to_end(&mut request).await.unwrap();
+            // Still writable after the client's half-close.
+            upstream.write_all(b"response").await.unwrap();
+            upstream.shutdown().await.unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            (request, response)
+        };
+        let ((close_reason, failed), (request, response)) = tokio::join!(relay, peers);
+        assert_eq!(close_reason, CloseReason::ClientEof);
+        assert_eq!(failed, None);
+        assert_eq!(request, b"request");
+        assert_eq!(response, b"response");
+        assert_eq!(counters.read(), 7);
+        assert_eq!(counters.written(), 8);
+    }
+
+    #[tokio::test]
+    async fn times_out_when_idle() {
+        let (mut client, client_side) = pair().await;
+        let (upstream_side, mut upstream) = pair().await;
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let counters = Counters::default();
+        let relay = relay(&client_side, &upstream_side, &timeouts, &counters);
+        // Traffic keeps the tunnel open past the idle timeout.
+        let peers = async {
+            for _ in 0..3 {
+                tokio::time::sleep(Duration::from_millis(120)).await;
+                upstream.write_all(b"x").await.unwrap();
+                client.read_exact(&mut [0; 1]).await.unwrap();
+            }
+            tokio::time::Instant::now()
+        };
+        let ((close_reason, _), last_sent) = tokio::join!(relay, peers);
+        assert_eq!(close_reason, CloseReason::Timeout(TimeoutKind::Idle));
+        assert!(last_sent.elapsed() < Duration::from_millis(400));
+        assert_eq!(counters.written(), 3);
+    }
+
+    #[tokio::test]
+    async fn only_pairs_plain_tcp() {
+        let (a, b) = pair().await;
+        assert!(tcp_pair(a, MaybeTls::Plain(b)).is_ok());
+        let (a, _) = pair().await;
+        let (duplex, _other) = tokio::io::duplex(64);
+        assert!(tcp_pair(a, duplex).is_err());
+    }
+}
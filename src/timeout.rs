@@ -0,0 +1,250 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+use crate::{
+    error::{ConfigError, ProxyStreamError, TimeoutKind},
+    DestinationAddress,
+};
+
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct Timeouts {
+    #[cfg_attr(feature = "serde", serde(with = "secs"))]
+    pub handshake: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(with = "secs"))]
+    pub connect: Option<Duration>,
+    // Ends a tunnel once neither direction has moved data for this long.
+    #[cfg_attr(feature = "serde", serde(with = "secs"))]
+    pub idle: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(with = "secs"))]
+    pub lifetime: Option<Duration>,
+}
+
+// Durations are written as (fractional) seconds, e.g. `handshake = 2.5`.
+#[cfg(feature = "serde")]
+pub(crate) mod secs {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        duration.map(|d| d.as_secs_f64()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<f64>::deserialize(deserializer)?
+            .map(|secs| Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+impl Timeouts {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        [self.handshake, self.connect, self.idle, self.lifetime]
+            .iter()
+            .flatten()
+            .all(|d| !d.is_zero())
+            .then_some(())
+            .ok_or(ConfigError::ZeroTimeout)
+    }
+}
+
+pub(crate) async fn timeout<T, E>(
+    duration: Option<Duration>,
+    kind: TimeoutKind,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, ProxyStreamError>
+where
+    E: Into<ProxyStreamError>,
+{
+    match duration {
+        Some(duration) => tokio::time::timeout(duration, future)
+            .await
+            .map_err(|_| ProxyStreamError::Timeout(kind))?
+            .map_err(|e| e.into()),
+        None => future.await.map_err(|e| e.into()),
+    }
+}
+
+pub(crate) async fn timeout_at<T, E>(
+    deadline: Option<Instant>,
+    kind: TimeoutKind,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, ProxyStreamError>
+where
+    E: Into<ProxyStreamError>,
+{
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future)
+            .await
+            .map_err(|_| ProxyStreamError::Timeout(kind))?
+            .map_err(|e| e.into()),
+        None => future.await.map_err(|e| e.into()),
+    }
+}
+
+pub(crate) async fn connect(
+    addr: &DestinationAddress,
+    duration: Option<Duration>,
+) -> std::io::Result<tokio::net::TcpStream> {
+    match duration {
+        Some(duration) => tokio::time::timeout(duration, addr.connect())
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))?,
+        None => addr.connect().await,
+    }
+}
+
+#[derive(Debug)]
+struct IdleElapsed;
+
+impl std::fmt::Display for IdleElapsed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "idle timeout elapsed")
+    }
+}
+
+impl std::error::Error for IdleElapsed {}
+
+// When a tunnel last moved data in either direction. Shared by both halves
+// so a quiet client does not end a download that is still flowing.
+#[derive(Clone)]
+pub(crate) struct Activity {
+    start: Instant,
+    last: Arc<AtomicU64>,
+}
+
+impl Activity {
+    pub(crate) fn new() -> Self {
+        Activity {
+            start: Instant::now(),
+            last: Arc::default(),
+        }
+    }
+
+    pub(crate) fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    pub(crate) fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+// Fails pending reads once nothing has moved for `timeout`, counting the
+// other direction's traffic when `activity` is shared with it.
+pub(crate) struct IdleTimeout<T> {
+    inner: T,
+    timeout: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    activity: Activity,
+}
+
+impl<T> IdleTimeout<T> {
+    pub(crate) fn new(inner: T, timeout: Option<Duration>) -> Self {
+        Self::shared(inner, timeout, Activity::new())
+    }
+
+    pub(crate) fn shared(inner: T, timeout: Option<Duration>, activity: Activity) -> Self {
+        Self {
+            inner,
+            timeout,
+            sleep: timeout.map(|timeout| Box::pin(tokio::time::sleep(timeout))),
+            activity,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleTimeout<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(res) => {
+                this.activity.touch();
+                if let (Some(sleep), Some(timeout)) = (this.sleep.as_mut(), this.timeout) {
+                    sleep.as_mut().reset(Instant::now() + timeout);
+                }
+                Poll::Ready(res)
+            }
+            Poll::Pending => {
+                let (Some(sleep), Some(timeout)) = (this.sleep.as_mut(), this.timeout) else {
+                    return Poll::Pending;
+                };
+                while sleep.as_mut().poll(cx).is_ready() {
+                    let deadline = this.activity.last() + timeout;
+                    if deadline <= Instant::now() {
+                        return Poll::Ready(Err(idle_elapsed()));
+                    }
+                    sleep.as_mut().reset(deadline);
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+pub(crate) fn idle_elapsed() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, IdleElapsed)
+}
+
+pub(crate) fn is_idle_elapsed(error: &std::io::Error) -> bool {
+    error.kind() == std::io::ErrorKind::TimedOut
+        && error.get_ref().is_some_and(|e| e.is::<IdleElapsed>())
+}
@@ -0,0 +1,281 @@
+// TLS between clients and the proxy itself (HTTPS proxies, SOCKS over TLS).
+// Without the `rustls` feature the acceptor and connector cannot be built,
+// so every stream stays plain.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[cfg(feature = "mitm")]
+pub(crate) use imp::{native_roots, provider};
+pub use imp::{TlsAcceptor, TlsConnector};
+
+pub(crate) enum MaybeTls<T> {
+    Plain(T),
+    #[cfg(feature = "rustls")]
+    Tls(Box<tokio_rustls::TlsStream<T>>),
+}
+
+impl<T> MaybeTls<T> {
+    pub(crate) fn is_plain(&self) -> bool {
+        match self {
+            MaybeTls::Plain(_) => true,
+            #[cfg(feature = "rustls")]
+            MaybeTls::Tls(_) => false,
+        }
+    }
+
+    pub(crate) fn into_plain(self) -> Result<T, Self> {
+        match self {
+            MaybeTls::Plain(socket) => Ok(socket),
+            #[cfg(feature = "rustls")]
+            tls @ MaybeTls::Tls(_) => Err(tls),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> MaybeTls<T> {
+    pub(crate) async fn accept(socket: T, acceptor: Option<&TlsAcceptor>) -> io::Result<Self> {
+        match acceptor {
+            Some(acceptor) => acceptor.accept(socket).await,
+            None => Ok(MaybeTls::Plain(socket)),
+        }
+    }
+
+    pub(crate) async fn connect(socket: T, connector: Option<&TlsConnector>) -> io::Result<Self> {
+        match connector {
+            Some(connector) => connector.connect(socket).await,
+            None => Ok(MaybeTls::Plain(socket)),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for MaybeTls<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(socket) => Pin::new(socket).poll_read(cx, buf),
+            #[cfg(feature = "rustls")]
+            MaybeTls::Tls(socket) => Pin::new(socket).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MaybeTls<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTls::Plain(socket) => Pin::new(socket).poll_write(cx, buf),
+            #[cfg(feature = "rustls")]
+            MaybeTls::Tls(socket) => Pin::new(socket).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(socket) => Pin::new(socket).poll_flush(cx),
+            #[cfg(feature = "rustls")]
+            MaybeTls::Tls(socket) => Pin::new(socket).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(socket) => Pin::new(socket).poll_shutdown(cx),
+            #[cfg(feature = "rustls")]
+            MaybeTls::Tls(socket) => Pin::new(socket).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTls::Plain(socket) => Pin::new(socket).poll_write_vectored(cx, bufs),
+            #[cfg(feature = "rustls")]
+            MaybeTls::Tls(socket) => Pin::new(socket).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            MaybeTls::Plain(socket) => socket.is_write_vectored(),
+            #[cfg(feature = "rustls")]
+            MaybeTls::Tls(socket) => socket.is_write_vectored(),
+        }
+    }
+}
+
+#[cfg(feature = "rustls")]
+mod imp {
+    use std::{
+        fmt, io,
+        sync::{Arc, OnceLock},
+    };
+
+    use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName};
+    use tokio::io::{AsyncRead, AsyncWrite};
+
+    use super::MaybeTls;
+    use crate::error::ConfigError;
+
+    // Pinned rather than the process default, which may be ambiguous when
+    // other crates enable further providers.
+    pub(crate) fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+        Arc::new(rustls::crypto::ring::default_provider())
+    }
+
+    pub(crate) fn native_roots() -> rustls::RootCertStore {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+        roots
+    }
+
+    #[derive(Clone)]
+    pub struct TlsAcceptor {
+        inner: tokio_rustls::TlsAcceptor,
+    }
+
+    impl TlsAcceptor {
+        pub fn new(config: Arc<rustls::ServerConfig>) -> Self {
+            TlsAcceptor {
+                inner: config.into(),
+            }
+        }
+
+        // Serves `cert_chain` (leaf first) with `key`, both PEM encoded.
+        pub fn from_pem(cert_chain: &[u8], key: &[u8]) -> Result<Self, ConfigError> {
+            let cert_chain = CertificateDer::pem_slice_iter(cert_chain)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| ConfigError::InvalidPem)?;
+            let key = PrivateKeyDer::from_pem_slice(key).map_err(|_| ConfigError::InvalidPem)?;
+            let config = rustls::ServerConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()?
+                .with_no_client_auth()
+                .with_single_cert(cert_chain, key)?;
+            Ok(Self::new(Arc::new(config)))
+        }
+
+        pub(crate) async fn accept<T: AsyncRead + AsyncWrite + Unpin>(
+            &self,
+            socket: T,
+        ) -> io::Result<MaybeTls<T>> {
+            let stream = self.inner.accept(socket).await?;
+            Ok(MaybeTls::Tls(Box::new(stream.into())))
+        }
+    }
+
+    impl fmt::Debug for TlsAcceptor {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("TlsAcceptor")
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct TlsConnector {
+        inner: tokio_rustls::TlsConnector,
+        server_name: ServerName<'static>,
+    }
+
+    impl TlsConnector {
+        // `server_name` is sent as SNI and checked against the proxy's
+        // certificate.
+        pub fn new(
+            config: Arc<rustls::ClientConfig>,
+            server_name: &str,
+        ) -> Result<Self, ConfigError> {
+            Ok(TlsConnector {
+                inner: config.into(),
+                server_name: ServerName::try_from(server_name.to_string())
+                    .map_err(|_| ConfigError::InvalidServerName)?,
+            })
+        }
+
+        // Trusts only the PEM encoded certificates in `roots`.
+        pub fn from_roots_pem(roots: &[u8], server_name: &str) -> Result<Self, ConfigError> {
+            let mut store = rustls::RootCertStore::empty();
+            for cert in CertificateDer::pem_slice_iter(roots) {
+                store.add(cert.map_err(|_| ConfigError::InvalidPem)?)?;
+            }
+            let config = rustls::ClientConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(store)
+                .with_no_client_auth();
+            Self::new(Arc::new(config), server_name)
+        }
+
+        // Trusts the platform's root certificates, loaded once per process.
+        pub fn native_roots(server_name: &str) -> Result<Self, ConfigError> {
+            static CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+            let config = match CONFIG.get() {
+                Some(config) => config.clone(),
+                None => {
+                    let config = rustls::ClientConfig::builder_with_provider(provider())
+                        .with_safe_default_protocol_versions()?
+                        .with_root_certificates(native_roots())
+                        .with_no_client_auth();
+                    CONFIG.get_or_init(|| Arc::new(config)).clone()
+                }
+            };
+            Self::new(config, server_name)
+        }
+
+        pub fn server_name(&self) -> &ServerName<'static> {
+            &self.server_name
+        }
+
+        pub(crate) async fn connect<T: AsyncRead + AsyncWrite + Unpin>(
+            &self,
+            socket: T,
+        ) -> io::Result<MaybeTls<T>> {
+            let stream = self.inner.connect(self.server_name.clone(), socket).await?;
+            Ok(MaybeTls::Tls(Box::new(stream.into())))
+        }
+    }
+
+    impl fmt::Debug for TlsConnector {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("TlsConnector")
+                .field("server_name", &self.server_name)
+                .finish()
+        }
+    }
+}
+
+#[cfg(not(feature = "rustls"))]
+mod imp {
+    use std::io;
+
+    use super::MaybeTls;
+
+    // Uninhabited: without the feature there is nothing to configure.
+    #[derive(Debug, Clone)]
+    pub enum TlsAcceptor {}
+
+    #[derive(Debug, Clone)]
+    pub enum TlsConnector {}
+
+    impl TlsAcceptor {
+        pub(crate) async fn accept<T>(&self, _: T) -> io::Result<MaybeTls<T>> {
+            match *self {}
+        }
+    }
+
+    impl TlsConnector {
+        pub(crate) async fn connect<T>(&self, _: T) -> io::Result<MaybeTls<T>> {
+            match *self {}
+        }
+    }
+}
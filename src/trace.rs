@@ -0,0 +1,157 @@
+// One `proxy_connection` span per accepted client, filled in as the
+// handshake learns the destination and user. Auth, negotiation, dialing the
+// upstream and relaying run in child spans, and failures and the final byte
+// counts are logged as events on it. Built without `tracing`, `Span` is a
+// zero-sized stand-in.
+
+pub(crate) use imp::Span;
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use std::future::Future;
+
+    use tracing::{field, Instrument};
+
+    use crate::{record::CloseReason, DestinationAddress, PeerInfo};
+
+    #[derive(Debug, Clone)]
+    pub(crate) struct Span {
+        inner: tracing::Span,
+    }
+
+    impl Span {
+        pub(crate) fn connection(protocol: &'static str, peer: &PeerInfo) -> Self {
+            Span {
+                inner: tracing::info_span!(
+                    "proxy_connection",
+                    protocol,
+                    peer = ?peer.addr,
+                    destination = field::Empty,
+                    user = field::Empty,
+                ),
+            }
+        }
+
+        pub(crate) fn auth(&self) -> Self {
+            Span {
+                inner: tracing::debug_span!(parent: &self.inner, "auth"),
+            }
+        }
+
+        pub(crate) fn negotiate(&self) -> Self {
+            Span {
+                inner: tracing::debug_span!(parent: &self.inner, "negotiate"),
+            }
+        }
+
+        pub(crate) fn request(&self, method: &str) -> Self {
+            Span {
+                inner: tracing::info_span!(
+                    parent: &self.inner,
+                    "request",
+                    method,
+                    destination = field::Empty,
+                ),
+            }
+        }
+
+        pub(crate) fn dial(&self) -> Self {
+            Span {
+                inner: tracing::debug_span!(parent: &self.inner, "dial"),
+            }
+        }
+
+        pub(crate) fn relay(&self) -> Self {
+            Span {
+                inner: tracing::debug_span!(parent: &self.inner, "relay"),
+            }
+        }
+
+        pub(crate) fn record_destination(&self, destination: &DestinationAddress) {
+            self.inner
+                .record("destination", field::display(destination));
+        }
+
+        pub(crate) fn record_user(&self, user: &str) {
+            self.inner.record("user", user);
+        }
+
+        pub(crate) fn event(&self, message: &'static str) {
+            tracing::debug!(parent: &self.inner, "{}", message);
+        }
+
+        pub(crate) fn failed(&self, message: &'static str, error: &dyn std::error::Error) {
+            // Error messages leave their sources out, so spell out the chain.
+            let mut chain = error.to_string();
+            let mut source = error.source();
+            while let Some(e) = source {
+                chain.push_str(": ");
+                chain.push_str(&e.to_string());
+                source = e.source();
+            }
+            tracing::info!(parent: &self.inner, error = %chain, "{}", message);
+        }
+
+        pub(crate) fn upstream(&self, upstream: Option<&DestinationAddress>) {
+            tracing::debug!(
+                parent: &self.inner,
+                upstream = upstream.map(field::display),
+                "upstream connected"
+            );
+        }
+
+        pub(crate) fn closed(&self, bytes_up: u64, bytes_down: u64, close_reason: CloseReason) {
+            tracing::info!(
+                parent: &self.inner,
+                bytes_up,
+                bytes_down,
+                close_reason = ?close_reason,
+                "relay closed"
+            );
+        }
+
+        pub(crate) fn instrument<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+            future.instrument(self.inner.clone())
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use std::future::Future;
+
+    use crate::{record::CloseReason, DestinationAddress, PeerInfo};
+
+    #[derive(Debug, Clone)]
+    pub(crate) struct Span;
+
+    impl Span {
+        pub(crate) fn connection(_: &'static str, _: &PeerInfo) -> Self {
+            Span
+        }
+        pub(crate) fn auth(&self) -> Self {
+            Span
+        }
+        pub(crate) fn negotiate(&self) -> Self {
+            Span
+        }
+        pub(crate) fn request(&self, _: &str) -> Self {
+            Span
+        }
+        pub(crate) fn dial(&self) -> Self {
+            Span
+        }
+        pub(crate) fn relay(&self) -> Self {
+            Span
+        }
+        pub(crate) fn record_destination(&self, _: &DestinationAddress) {}
+        pub(crate) fn record_user(&self, _: &str) {}
+        pub(crate) fn event(&self, _: &'static str) {}
+        pub(crate) fn failed(&self, _: &'static str, _: &dyn std::error::Error) {}
+        pub(crate) fn upstream(&self, _: Option<&DestinationAddress>) {}
+        pub(crate) fn closed(&self, _: u64, _: u64, _: CloseReason) {}
+        pub(crate) fn instrument<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+            future
+        }
+    }
+}